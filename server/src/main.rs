@@ -65,10 +65,10 @@ impl AppState {
 }
 
 async fn push_handler(
-    Query(query): Query<Vec<(String, f32)>>,
+    Query(query): Query<Vec<(String, f64)>>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    let mut map = HashMap::<String, Vec<f32>>::new();
+    let mut map = HashMap::<String, Vec<f64>>::new();
     let mut values = state.values.lock().await;
     for (k, v) in query {
         values.push(k.clone(), &[v]);
@@ -92,7 +92,7 @@ async fn push_handler2(
         Ok(v) => v,
         Err(e) => return format!("failed to decode base64: {}", e),
     };
-    let v = match rmp_serde::from_slice::<HashMap<String, Vec<f32>>>(&v) {
+    let v = match rmp_serde::from_slice::<HashMap<String, Vec<f64>>>(&v) {
         Ok(v) => v,
         Err(e) => return format!("failed to decode message pack: {}", e),
     };
@@ -3,7 +3,7 @@ use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Serialize)]
 pub struct Values {
-    values: HashMap<String, VecDeque<f32>>,
+    values: HashMap<String, VecDeque<f64>>,
     #[serde(skip)]
     max_len: usize,
 }
@@ -22,7 +22,7 @@ impl Values {
         }
     }
 
-    pub fn push(&mut self, key: String, values: &[f32]) {
+    pub fn push(&mut self, key: String, values: &[f64]) {
         let vec = self
             .values
             .entry(key)
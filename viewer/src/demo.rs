@@ -0,0 +1,69 @@
+use crate::nits::{NitsCommand, NitsRelativeCarCount, NitsTick, DEFAULT_PAYLOAD_BITS};
+use std::collections::HashMap;
+
+const TICKS_PER_SECOND: f64 = 60.0;
+
+// サーバー無しで動作を確認するための疑似データ生成器
+#[derive(Debug, Default)]
+pub struct Demo {
+    tick: u32,
+    last_time: Option<f64>,
+}
+
+impl Demo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 前回呼び出しからの経過時間分のティックを生成する
+    pub fn update(&mut self, time: f64) -> Option<HashMap<String, Vec<f64>>> {
+        let last_time = *self.last_time.get_or_insert(time);
+        let ticks = ((time - last_time) * TICKS_PER_SECOND) as u32;
+        if ticks == 0 {
+            return None;
+        }
+        self.last_time = Some(last_time + ticks as f64 / TICKS_PER_SECOND);
+
+        let mut data: HashMap<String, Vec<f64>> = HashMap::new();
+        for _ in 0..ticks {
+            for (k, v) in self.generate_tick() {
+                data.entry(k).or_default().push(v);
+            }
+            self.tick = self.tick.wrapping_add(1);
+        }
+        Some(data)
+    }
+
+    fn generate_tick(&self) -> Vec<(String, f64)> {
+        let t = self.tick as f64 / TICKS_PER_SECOND;
+        // 前後1両ずつの3両編成として NITS 信号を組み立てる
+        let car_count_front = 1;
+        let car_count_back = 1;
+        let mut nits_tick = NitsTick::new(NitsCommand::from_parts(
+            0x01,
+            car_count_back << 5 | car_count_front,
+        ));
+        nits_tick.add_command(
+            NitsRelativeCarCount::new(-1),
+            NitsCommand::from_parts(0x10, self.tick / 60),
+        );
+        nits_tick.add_command(
+            NitsRelativeCarCount::new(0),
+            NitsCommand::from_parts(0x11, 1 << (self.tick / 15 % DEFAULT_PAYLOAD_BITS)),
+        );
+        nits_tick.add_command(
+            NitsRelativeCarCount::new(1),
+            NitsCommand::from_parts(0x12, ((t.sin() + 1.0) * 1000.0) as u32),
+        );
+        [
+            ("Sin", t.sin()),
+            ("Cos", t.cos()),
+            ("Saw", t.fract()),
+            ("Counter", self.tick as f64),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .chain(nits_tick.to_channels())
+        .collect()
+    }
+}
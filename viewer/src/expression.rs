@@ -0,0 +1,176 @@
+// 既存のキーを組み合わせて計算するキーの式
+// 四則演算と括弧が使える ("A - B", "(\"NITS N01\" + 1) * 2" など)
+// 記号や空白を含むキーは "..." で囲む
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Key(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let expr = parser.expr()?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(format!("Unexpected '{}' at {}", c, parser.pos)),
+        }
+    }
+
+    // 式に出てくるキー (重複は除く)
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = vec![];
+        self.collect_keys(&mut keys);
+        keys
+    }
+
+    fn collect_keys<'a>(&'a self, keys: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Key(key) => {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key);
+                }
+            }
+            Expr::Neg(e) => e.collect_keys(keys),
+            Expr::Binary(_, l, r) => {
+                l.collect_keys(keys);
+                r.collect_keys(keys);
+            }
+        }
+    }
+
+    // value はキーの値 (無ければ NaN) を返す
+    pub fn eval(&self, value: &impl Fn(&str) -> f64) -> f64 {
+        match self {
+            Expr::Number(v) => *v,
+            Expr::Key(key) => value(key),
+            Expr::Neg(e) => -e.eval(value),
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.eval(value), r.eval(value));
+                match op {
+                    Op::Add => l + r,
+                    Op::Sub => l - r,
+                    Op::Mul => l * r,
+                    Op::Div => l / r,
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('+') => Op::Add,
+                Some('-') => Op::Sub,
+                _ => return Ok(expr),
+            };
+            self.bump();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.factor()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('*') => Op::Mul,
+                Some('/') => Op::Div,
+                _ => return Ok(expr),
+            };
+            self.bump();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.factor()?));
+        }
+    }
+
+    // factor := '-' factor | '(' expr ')' | 数値 | キー
+    fn factor(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.peek() {
+            Some('-') => {
+                self.bump();
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.bump();
+                let expr = self.expr()?;
+                self.skip_whitespace();
+                match self.bump() {
+                    Some(')') => Ok(expr),
+                    _ => Err(format!("Missing ')' for '(' at {}", start)),
+                }
+            }
+            Some('"') => {
+                self.bump();
+                let end = self.text[self.pos..]
+                    .find('"')
+                    .ok_or_else(|| format!("Missing '\"' for '\"' at {}", start))?;
+                let key = self.text[self.pos..self.pos + end].to_owned();
+                self.pos += end + 1;
+                Ok(Expr::Key(key))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.bump();
+                }
+                let number = &self.text[start..self.pos];
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("Invalid number \"{}\" at {}", number, start))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.')
+                {
+                    self.bump();
+                }
+                Ok(Expr::Key(self.text[start..self.pos].to_owned()))
+            }
+            Some(c) => Err(format!("Unexpected '{}' at {}", c, start)),
+            None => Err("Unexpected end of expression".to_owned()),
+        }
+    }
+}
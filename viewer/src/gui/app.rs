@@ -1,18 +1,54 @@
 use crate::{
-    settings::Settings,
+    demo::Demo,
+    expression::Expr,
+    nits::{load_command_type_map, NitsCommandType, NitsProtocol, PAYLOAD_BITS_RANGE},
+    recorder::Recorder,
+    settings::{LineColors, SampleAlignment, Settings, MAX_RETENTION_SECONDS},
     values::Values,
+    wire_format::{self, WireFormat},
 };
 use super::{
     digital_table::DigitalTableWindow,
     graph::{LineGraph, XYGraph},
+    measurements::Measurements,
     nits_timeline::NitsTimelineWindow,
+    overview::OverviewWindow,
     table::TableWindow,
 };
-use egui::{ahash::HashMap, Context};
+use egui::{ahash::HashMap, collapsing_header::CollapsingState, Context, Id, LayerId, Order};
 use egui_file::FileDialog;
 use ewebsock::{WsMessage, WsReceiver, WsSender};
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+// 再接続を試みる間隔 (秒)。失敗するたびに倍にする
+const RECONNECT_DELAY_MIN: f64 = 0.5;
+const RECONNECT_DELAY_MAX: f64 = 30.0;
+
+fn default_reconnect_delay() -> f64 {
+    RECONNECT_DELAY_MIN
+}
+
+// 受信レートを求めるのに使う直近の期間 (秒)
+const MESSAGE_RATE_WINDOW: f64 = 5.0;
+
+// Connection log に残す件数
+const CONNECTION_LOG_LEN: usize = 50;
+
+// 接続に関するイベント。time は ctx.input の time
+struct ConnectionLogEntry {
+    time: f64,
+    message: String,
+    error: bool,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum Window {
@@ -21,6 +57,7 @@ pub enum Window {
     Table(Box<TableWindow>),
     DigitalTable(Box<DigitalTableWindow>),
     NitsTimeline(Box<NitsTimelineWindow>),
+    Overview(Box<OverviewWindow>),
 }
 
 impl Window {
@@ -31,53 +68,1193 @@ impl Window {
             Window::Table(w) => w.show(ctx, open, values),
             Window::DigitalTable(w) => w.show(ctx, open, values),
             Window::NitsTimeline(w) => w.show(ctx, open, values),
+            Window::Overview(w) => w.show(ctx, open, values),
+        }
+    }
+
+    fn id(&self) -> Id {
+        match self {
+            Window::LineGraph(w) => w.id(),
+            Window::XYGraph(w) => w.id(),
+            Window::Table(w) => w.id(),
+            Window::DigitalTable(w) => w.id(),
+            Window::NitsTimeline(w) => w.id(),
+            Window::Overview(w) => w.id(),
+        }
+    }
+
+    fn set_id(&mut self, id: Id) {
+        match self {
+            Window::LineGraph(w) => w.set_id(id),
+            Window::XYGraph(w) => w.set_id(id),
+            Window::Table(w) => w.set_id(id),
+            Window::DigitalTable(w) => w.set_id(id),
+            Window::NitsTimeline(w) => w.set_id(id),
+            Window::Overview(w) => w.set_id(id),
+        }
+    }
+
+    fn title(&self) -> &str {
+        match self {
+            Window::LineGraph(w) => w.title(),
+            Window::XYGraph(w) => w.title(),
+            Window::Table(w) => w.title(),
+            Window::DigitalTable(w) => w.title(),
+            Window::NitsTimeline(w) => w.title(),
+            Window::Overview(w) => w.title(),
+        }
+    }
+
+    // ウィンドウを最前面に移動し、折りたたまれていれば展開する
+    fn bring_to_front(&self, ctx: &Context) {
+        let id = self.id();
+        ctx.move_to_top(LayerId::new(Order::Middle, id));
+        let mut state = CollapsingState::load_with_default_open(ctx, id.with("collapsing"), true);
+        state.set_open(true);
+        state.store(ctx);
+    }
+}
+
+// ウィンドウの Id を new_id で振り直す (NitsTimeline の描画先のグラフも付け替える)
+fn reassign_ids<'a>(windows: impl Iterator<Item = &'a mut Window>, mut new_id: impl FnMut() -> Id) {
+    let mut windows: Vec<_> = windows.collect();
+    let mut ids = HashMap::default();
+    for window in &mut windows {
+        let id = new_id();
+        ids.insert(window.id(), id);
+        window.set_id(id);
+    }
+    for window in windows {
+        if let Window::NitsTimeline(timeline) = window {
+            if let Some(id) = timeline.plot_graph().and_then(|id| ids.get(&id)) {
+                timeline.set_plot_graph(*id);
+            }
+        }
+    }
+}
+
+// File > Save layout で保存するウィンドウ構成
+// positions は windows と同じ並びのウィンドウの位置
+#[derive(Serialize)]
+struct LayoutRef<'a> {
+    server: &'a str,
+    windows: &'a [(Window, bool)],
+    positions: Vec<Option<egui::Pos2>>,
+}
+
+#[derive(Deserialize)]
+struct Layout {
+    server: String,
+    windows: Vec<(Window, bool)>,
+    #[serde(default)]
+    positions: Vec<Option<egui::Pos2>>,
+}
+
+// ダッシュボードのテンプレート (TOML)
+// レイアウトと違って接続先は持たず、ウィンドウの位置を持つ。読み込むと今のウィンドウに追加する
+#[derive(Serialize, Deserialize)]
+struct Template {
+    windows: Vec<TemplateWindow>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TemplateWindow {
+    window: Window,
+    open: bool,
+    #[serde(default)]
+    position: Option<egui::Pos2>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+// 終了時に使用していたデータの取得元
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum SourceConfig {
+    WebSocket,
+    Demo,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct App {
+    id: u32,
+    server: String,
+    #[serde(default)]
+    source: Option<SourceConfig>,
+    #[serde(skip, default)]
+    ws: Option<(WsSender, WsReceiver)>,
+    #[serde(skip, default)]
+    connection_state: ConnectionState,
+    #[serde(skip, default)]
+    connect_error: Option<String>,
+    #[serde(skip, default)]
+    connection_log: VecDeque<ConnectionLogEntry>,
+    // 次に再接続を試みる時刻 (ctx.input の time) と、その次に待つ秒数
+    #[serde(skip, default)]
+    reconnect_at: Option<f64>,
+    #[serde(skip, default = "default_reconnect_delay")]
+    reconnect_delay: f64,
+    #[serde(skip, default)]
+    demo: Option<Demo>,
+    // 直近にデータを受信した時刻 (ctx.input の time)
+    #[serde(skip, default)]
+    message_times: VecDeque<f64>,
+    // 一時停止中は受信したデータを values に入れず溜めておく
+    #[serde(skip, default)]
+    paused: bool,
+    #[serde(skip, default)]
+    paused_data: VecDeque<(HashMap<String, Vec<f64>>, f64)>,
+    values: Values,
+    settings: Rc<RefCell<Settings>>,
+    windows: Vec<(Window, bool)>,
+    // メインテーブルに平均と標準偏差の列を表示する
+    #[serde(default)]
+    show_mean_stddev: bool,
+    #[serde(default)]
+    measurements: Measurements,
+    #[serde(default)]
+    measurements_open: bool,
+    // グラフで印を付けた時刻 (全ウィンドウで共通)
+    #[serde(skip, default)]
+    marked_time: Option<f64>,
+    // サーバーに送るコマンドの入力欄
+    #[serde(default)]
+    command_open: bool,
+    #[serde(default)]
+    command_text: String,
+    #[serde(skip, default)]
+    command_error: Option<String>,
+    #[serde(skip, default)]
+    open_dialog: Option<FileDialog>,
+    // 読み込み方を選んでもらっている CSV
+    #[serde(skip, default)]
+    pending_csv: Option<PathBuf>,
+    #[serde(skip, default)]
+    command_type_names_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    payload_schemas_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    export_protocol_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    import_protocol_dialog: Option<FileDialog>,
+    // 今の定義と食い違うため、どちらを使うか選んでもらっている読み込んだ定義
+    #[serde(skip, default)]
+    pending_protocol: Option<NitsProtocol>,
+    #[serde(skip, default)]
+    save_dialog: Option<FileDialog>,
+    // メインテーブルでチェックしたキー (チェックした順に CSV に書き出す)
+    #[serde(default)]
+    selected_keys: Vec<String>,
+    #[serde(skip, default)]
+    save_selected_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    measurements_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    save_layout_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    record_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    recorder: Option<Recorder>,
+    #[serde(skip, default)]
+    load_layout_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    save_template_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    apply_template_dialog: Option<FileDialog>,
+    #[serde(skip, default)]
+    pending_retention_period: Option<u32>,
+    // Retention period メニューで入力中の秒数
+    #[serde(skip, default)]
+    retention_input: Option<u32>,
+    // メインテーブルで開いているキーのグループ
+    #[serde(default)]
+    expanded_groups: BTreeSet<String>,
+    // Key mapping メニューで入力中の受け取るキーと表示するキー
+    #[serde(skip, default)]
+    key_rename_input: (String, String),
+    // Computed keys メニューで入力中の名前と式
+    #[serde(skip, default)]
+    computed_key_input: (String, String),
+    #[serde(skip, default)]
+    computed_key_error: Option<String>,
+    #[serde(skip, default)]
+    file_error: Option<String>,
+}
+
+impl App {
+    pub fn new(cc: &eframe::CreationContext) -> Self {
+        let mut app = Self::restore(cc).unwrap_or_else(|| Self::init(cc));
+        cc.egui_ctx.set_theme(app.settings.borrow().theme);
+
+        // ?demo を付けて開かれた場合はサーバーに接続せず疑似データを表示する
+        #[cfg(target_arch = "wasm32")]
+        if cc
+            .integration_info
+            .web_info
+            .location
+            .query_map
+            .contains_key("demo")
+        {
+            app.source = Some(SourceConfig::Demo);
+            app.start_source(&cc.egui_ctx);
+            return app;
+        }
+
+        if app.settings.borrow().auto_connect {
+            app.start_source(&cc.egui_ctx);
+        } else {
+            app.source = None;
+        }
+        app
+    }
+
+    fn restore(cc: &eframe::CreationContext) -> Option<Self> {
+        let mut app: App = eframe::get_value(cc.storage?, eframe::APP_KEY)?;
+        app.values.set_settings(Rc::clone(&app.settings));
+        // 値を保存しない設定では空のキューが長さ 0 で復元されるので、保持期間に合わせ直す
+        app.values.set_max_len();
+        Some(app)
+    }
+
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+    fn init(cc: &eframe::CreationContext) -> Self {
+        #[cfg(target_arch = "wasm32")]
+        let server = {
+            let location = &cc.integration_info.web_info.location;
+            format!("ws://{}/socket", location.host)
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let server = "ws://127.0.0.1:8080/socket".into();
+        let settings = Rc::new(RefCell::new(Settings::default()));
+        Self {
+            id: 0,
+            server,
+            source: None,
+            ws: None,
+            connection_state: ConnectionState::Disconnected,
+            connect_error: None,
+            connection_log: VecDeque::new(),
+            reconnect_at: None,
+            reconnect_delay: RECONNECT_DELAY_MIN,
+            demo: None,
+            message_times: VecDeque::new(),
+            paused: false,
+            paused_data: VecDeque::new(),
+            values: Values::new(Rc::clone(&settings)),
+            settings,
+            windows: vec![],
+            show_mean_stddev: false,
+            measurements: Measurements::default(),
+            measurements_open: false,
+            marked_time: None,
+            command_open: false,
+            command_text: String::new(),
+            command_error: None,
+            open_dialog: None,
+            pending_csv: None,
+            command_type_names_dialog: None,
+            payload_schemas_dialog: None,
+            export_protocol_dialog: None,
+            import_protocol_dialog: None,
+            pending_protocol: None,
+            save_dialog: None,
+            selected_keys: vec![],
+            save_selected_dialog: None,
+            measurements_dialog: None,
+            save_layout_dialog: None,
+            record_dialog: None,
+            recorder: None,
+            load_layout_dialog: None,
+            save_template_dialog: None,
+            apply_template_dialog: None,
+            pending_retention_period: None,
+            retention_input: None,
+            expanded_groups: BTreeSet::new(),
+            key_rename_input: Default::default(),
+            computed_key_input: Default::default(),
+            computed_key_error: None,
+            file_error: None,
+        }
+    }
+
+    fn start_source(&mut self, ctx: &Context) {
+        match self.source {
+            Some(SourceConfig::WebSocket) => self.connect(ctx),
+            Some(SourceConfig::Demo) => self.demo = Some(Demo::new()),
+            None => {}
+        }
+    }
+
+    fn log_connection(&mut self, ctx: &Context, message: String, error: bool) {
+        if self.connection_log.len() == CONNECTION_LOG_LEN {
+            self.connection_log.pop_front();
+        }
+        self.connection_log.push_back(ConnectionLogEntry {
+            time: ctx.input(|i| i.time),
+            message,
+            error,
+        });
+    }
+
+    fn connect(&mut self, ctx: &Context) {
+        self.reconnect_at = None;
+        if let Err(e) = validate_server_url(&self.server) {
+            self.log_connection(ctx, e.clone(), true);
+            self.connect_error = Some(e);
+            self.source = None;
+            return;
+        }
+        self.connect_error = None;
+        self.log_connection(ctx, format!("Connecting to {}", self.server), false);
+        let wakeup = {
+            let ctx = ctx.clone();
+            move || ctx.request_repaint()
+        };
+        self.ws = match ewebsock::connect_with_wakeup(&self.server, Default::default(), wakeup) {
+            Ok(ws) => Some(ws),
+            Err(e) => {
+                log::error!("failed to init websocket {}", e);
+                self.log_connection(ctx, e.clone(), true);
+                self.connect_error = Some(e);
+                None
+            }
+        };
+        self.connection_state = if self.ws.is_some() {
+            ConnectionState::Connecting
+        } else {
+            ConnectionState::Disconnected
+        };
+    }
+
+    // 接続が切れたので、間隔を空けて再接続する
+    fn schedule_reconnect(&mut self, ctx: &Context) {
+        self.ws = None;
+        self.connection_state = ConnectionState::Disconnected;
+        self.reconnect_at = Some(ctx.input(|i| i.time) + self.reconnect_delay);
+        self.reconnect_delay = (self.reconnect_delay * 2.0).min(RECONNECT_DELAY_MAX);
+    }
+
+    fn poll_reconnect(&mut self, ctx: &Context) {
+        let Some(reconnect_at) = self.reconnect_at else {
+            return;
+        };
+        let remaining = reconnect_at - ctx.input(|i| i.time);
+        if remaining <= 0.0 {
+            self.connect(ctx);
+        } else {
+            ctx.request_repaint_after(Duration::from_secs_f64(remaining));
+        }
+    }
+
+    fn connection_status(&self, ui: &mut egui::Ui) {
+        let state = if self.demo.is_some() {
+            ConnectionState::Connected
+        } else {
+            self.connection_state
+        };
+        let (color, text) = match state {
+            ConnectionState::Disconnected => (egui::Color32::RED, "Disconnected"),
+            ConnectionState::Connecting => (egui::Color32::YELLOW, "Connecting"),
+            ConnectionState::Connected => (egui::Color32::GREEN, "Connected"),
+        };
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+        ui.painter().circle_filled(rect.center(), 5.0, color);
+        response.on_hover_ui(|ui| {
+            ui.label(text);
+            if let Some(e) = &self.connect_error {
+                ui.label(format!("Last error: {}", e));
+            }
+        });
+        // 最後のイベントがエラーなら赤いバッジを出す
+        if let Some(entry) = self.connection_log.back().filter(|e| e.error) {
+            ui.label(
+                egui::RichText::new(" ! ")
+                    .strong()
+                    .color(egui::Color32::WHITE)
+                    .background_color(ui.visuals().error_fg_color),
+            )
+            .on_hover_text(&entry.message);
+        }
+    }
+
+    fn connection_log_ui(&mut self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new(format!("Connection log ({})", self.connection_log.len()))
+            .id_salt("connection_log")
+            .show(ui, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(120.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for entry in &self.connection_log {
+                            let text = format!("[{:.1}s] {}", entry.time, entry.message);
+                            if entry.error {
+                                ui.colored_label(ui.visuals().error_fg_color, text);
+                            } else {
+                                ui.label(text);
+                            }
+                        }
+                    });
+                if ui.button("Clear").clicked() {
+                    self.connection_log.clear();
+                }
+            });
+    }
+
+    // 接続状態と最後の受信からの経過時間、受信レート
+    fn feed_status(&self, ui: &mut egui::Ui) {
+        let connected = self.demo.is_some() || self.connection_state == ConnectionState::Connected;
+        let now = ui.input(|i| i.time);
+        let elapsed = self.message_times.back().map(|t| now - t);
+        let stale = elapsed.is_none_or(|e| e > self.settings.borrow().stale_timeout);
+        let mut text = if connected {
+            "Connected"
+        } else {
+            "Disconnected"
+        }
+        .to_owned();
+        if let Some(elapsed) = elapsed {
+            let rate = self
+                .message_times
+                .iter()
+                .filter(|t| **t >= now - MESSAGE_RATE_WINDOW)
+                .count() as f64
+                / MESSAGE_RATE_WINDOW;
+            text += &format!(", last message {:.1}s ago, {:.1} msg/s", elapsed, rate);
+        }
+        let color = if connected && !stale {
+            ui.visuals().weak_text_color()
+        } else {
+            ui.visuals().error_fg_color
+        };
+        ui.horizontal(|ui| {
+            ui.colored_label(color, text);
+            let dropped = self.values.dropped_ticks();
+            if dropped > 0 {
+                ui.colored_label(
+                    ui.visuals().warn_fg_color,
+                    format!("{} dropped ticks", dropped),
+                )
+                .on_hover_text(format!(
+                    "{} gaps in the retained data",
+                    self.values.gaps().len()
+                ));
+            }
+        });
+        if connected {
+            // データが途絶えても経過時間の表示を更新する
+            ui.ctx().request_repaint_after(Duration::from_secs(1));
+        }
+    }
+
+    // NITS Timeline から要求されたチャンネルをグラフに追加する
+    fn plot_nits_commands(&mut self) {
+        for i in 0..self.windows.len() {
+            let Window::NitsTimeline(timeline) = &mut self.windows[i].0 else {
+                continue;
+            };
+            let Some(key) = timeline.take_plot_request() else {
+                continue;
+            };
+            // 派生チャンネルは選ばれたものだけ記録する
+            if !self.values.enable_nits_channel(&key) {
+                log::warn!("key limit reached, cannot plot \"{}\"", key);
+                continue;
+            }
+            let Window::NitsTimeline(timeline) = &self.windows[i].0 else {
+                continue;
+            };
+            let target = timeline.plot_graph();
+            let graph = self.windows.iter_mut().find_map(|(w, _)| match w {
+                Window::LineGraph(g) if Some(g.id()) == target => Some(g),
+                _ => None,
+            });
+            if let Some(graph) = graph {
+                graph.add_key(key);
+            } else {
+                let graph = LineGraph::new(self.id, key);
+                self.id += 1;
+                if let Window::NitsTimeline(timeline) = &mut self.windows[i].0 {
+                    timeline.set_plot_graph(graph.id());
+                }
+                self.windows
+                    .push((Window::LineGraph(Box::new(graph)), true));
+            }
+        }
+    }
+
+    // Overview でクリックされたキーのグラフを開く
+    fn open_overview_graphs(&mut self) {
+        let keys: Vec<String> = self
+            .windows
+            .iter_mut()
+            .filter_map(|(w, _)| match w {
+                Window::Overview(overview) => overview.take_open_request(),
+                _ => None,
+            })
+            .collect();
+        for key in keys {
+            self.windows.push((
+                Window::LineGraph(Box::new(LineGraph::new(self.id, key))),
+                true,
+            ));
+            self.id += 1;
+        }
+    }
+
+    // グラフで取り込んだカーソル位置の値を Measurements に追加する
+    fn capture_measurements(&mut self) {
+        for (window, _) in &mut self.windows {
+            if let Window::LineGraph(graph) = window {
+                if let Some(measurement) = graph.take_capture_request() {
+                    self.measurements.push(measurement);
+                    self.measurements_open = true;
+                }
+            }
+        }
+    }
+
+    // ウィンドウで起きたファイルのエラーをダイアログに出す
+    fn collect_file_errors(&mut self) {
+        for (window, _) in &mut self.windows {
+            let error = match window {
+                Window::LineGraph(graph) => graph.take_file_error(),
+                Window::Table(table) => table.take_file_error(),
+                _ => None,
+            };
+            if let Some(error) = error {
+                self.file_error = Some(error);
+            }
+        }
+    }
+
+    // グラフで付けた印を取り込み、全ウィンドウに反映する
+    fn sync_marked_time(&mut self) {
+        for (window, _) in &mut self.windows {
+            if let Window::LineGraph(graph) = window {
+                if let Some(time) = graph.take_mark_request() {
+                    self.marked_time = time;
+                }
+            }
+        }
+        let tick = self
+            .marked_time
+            .and_then(|time| self.values.nits_tick_at(time));
+        for (window, _) in &mut self.windows {
+            match window {
+                Window::LineGraph(graph) => graph.set_marked_time(self.marked_time),
+                Window::NitsTimeline(timeline) => timeline.set_marked_tick(tick),
+                _ => {}
+            }
+        }
+    }
+
+    fn receive<S: std::hash::BuildHasher>(
+        &mut self,
+        data: std::collections::HashMap<String, Vec<f64>, S>,
+        time: f64,
+    ) {
+        self.record(&data, time);
+        self.message_times.push_back(time);
+        while self
+            .message_times
+            .front()
+            .is_some_and(|t| *t < time - MESSAGE_RATE_WINDOW)
+        {
+            self.message_times.pop_front();
+        }
+        if self.paused {
+            // 保持期間を超える分は再開しても捨てられるので溜めない
+            let max_len = self.settings.borrow().max_len();
+            self.paused_data
+                .push_back((data.into_iter().collect(), time));
+            while self.paused_data.len() > max_len {
+                self.paused_data.pop_front();
+            }
+        } else {
+            self.values.add_data(data, Some(time));
+        }
+    }
+
+    // 保持期間や一時停止とは関係なく、受信したものをそのまま書き出す
+    fn record<S: std::hash::BuildHasher>(
+        &mut self,
+        data: &std::collections::HashMap<String, Vec<f64>, S>,
+        time: f64,
+    ) {
+        #[derive(Serialize)]
+        struct Line<'a, T> {
+            time: f64,
+            data: &'a T,
+        }
+
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+        let ticks = data.values().map(Vec::len).max().unwrap_or(0);
+        let rotation = self.settings.borrow().record_rotation.clone();
+        if let Err(e) = recorder.write(&Line { time, data }, ticks, time, &rotation) {
+            self.file_error = Some(format!("Recording stopped: {}", e));
+            self.stop_recording();
+        }
+    }
+
+    fn start_recording(&mut self, path: &Path) {
+        self.stop_recording();
+        match Recorder::create(path) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => self.file_error = Some(format!("Failed to start recording: {}", e)),
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.finish() {
+                self.file_error = Some(format!("Failed to finish recording: {}", e));
+            }
+        }
+    }
+
+    fn resume(&mut self) {
+        self.paused = false;
+        while let Some((data, time)) = self.paused_data.pop_front() {
+            self.values.add_data(data, Some(time));
+        }
+    }
+
+    fn save_layout(&self, ctx: &Context, path: &Path) -> Result<(), String> {
+        let layout = LayoutRef {
+            server: &self.server,
+            windows: &self.windows,
+            positions: self
+                .windows
+                .iter()
+                .map(|(window, _)| ctx.memory(|m| m.area_rect(window.id())).map(|r| r.min))
+                .collect(),
+        };
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &layout).map_err(|e| e.to_string())
+    }
+
+    // 現在のウィンドウを読み込んだものに置き換える
+    fn load_layout(&mut self, ctx: &Context, path: &Path) -> Result<(), String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut layout: Layout =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())?;
+        self.assign_new_ids(layout.windows.iter_mut().map(|(w, _)| w));
+        for ((window, _), position) in layout.windows.iter().zip(layout.positions) {
+            if let Some(pos) = position {
+                super::set_initial_window_pos(ctx, window.id(), pos);
+            }
+        }
+        self.server = layout.server;
+        self.windows = layout.windows;
+        Ok(())
+    }
+
+    // 開いているウィンドウや以前読み込んだレイアウトと Id が重ならないように振り直す
+    fn assign_new_ids<'a>(&mut self, windows: impl Iterator<Item = &'a mut Window>) {
+        let next_id = &mut self.id;
+        reassign_ids(windows, || {
+            let id = Id::new(("layout", *next_id));
+            *next_id += 1;
+            id
+        });
+    }
+
+    fn save_template(&self, ctx: &Context, path: &Path) -> Result<(), String> {
+        let positions: Vec<_> = self
+            .windows
+            .iter()
+            .map(|(window, _)| ctx.memory(|m| m.area_rect(window.id())).map(|r| r.min))
+            .collect();
+        // TOML の整数は i64 までなので、Id を i64 に収まる値に振り直した複製を書き出す
+        // (Id は読み込むときに振り直すので値そのものに意味は無い)
+        let json = serde_json::to_value(&self.windows).map_err(|e| e.to_string())?;
+        let mut windows: Vec<(Window, bool)> =
+            serde_json::from_value(json).map_err(|e| e.to_string())?;
+        let mut n = 0_u64;
+        reassign_ids(windows.iter_mut().map(|(window, _)| window), || loop {
+            let id = Id::new(("template", n));
+            n += 1;
+            if id.value() <= i64::MAX as u64 {
+                break id;
+            }
+        });
+        let template = Template {
+            windows: windows
+                .into_iter()
+                .zip(positions)
+                .map(|((window, open), position)| TemplateWindow {
+                    window,
+                    open,
+                    position,
+                })
+                .collect(),
+        };
+        let text = toml::to_string_pretty(&template).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+
+    // テンプレートのウィンドウを新しい Id で追加する (まだ無いキーはデータが届くまで空のまま)
+    fn apply_template(&mut self, ctx: &Context, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let Template {
+            windows: mut template,
+        } = toml::from_str(&text).map_err(|e| e.to_string())?;
+        self.assign_new_ids(template.iter_mut().map(|t| &mut t.window));
+        for t in template {
+            if let Some(pos) = t.position {
+                super::set_initial_window_pos(ctx, t.window.id(), pos);
+            }
+            self.windows.push((t.window, t.open));
+        }
+        Ok(())
+    }
+
+    // メモリ使用量の目安を超えるときは確認ダイアログを出す
+    fn request_retention_period(&mut self, retention_period: u32) {
+        let projected = self.values.approx_memory_bytes(retention_period as usize);
+        if projected > self.settings.borrow().memory_budget_bytes() {
+            self.pending_retention_period = Some(retention_period);
+        } else {
+            self.set_retention_period(retention_period);
+        }
+    }
+
+    fn set_retention_period(&mut self, retention_period: u32) {
+        self.settings.borrow_mut().retention_period = retention_period;
+        self.values.set_max_len();
+    }
+
+    fn retention_period_dialog(&mut self, ctx: &Context) {
+        let Some(retention_period) = self.pending_retention_period else {
+            return;
+        };
+        let projected = self.values.approx_memory_bytes(retention_period as usize);
+        let budget = self.settings.borrow().memory_budget_bytes();
+        egui::Window::new("Confirm retention period")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "This retention period may use about {} MiB, which exceeds the memory budget of {} MiB.",
+                    projected / 1024 / 1024,
+                    budget / 1024 / 1024
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Apply").clicked() {
+                        self.set_retention_period(retention_period);
+                        self.pending_retention_period = None;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.pending_retention_period = None;
+                    }
+                });
+            });
+    }
+
+    fn protocol(&self) -> NitsProtocol {
+        let settings = self.settings.borrow();
+        NitsProtocol {
+            command_type_names: settings.command_type_names.clone(),
+            payload_schemas: settings.payload_schemas.clone(),
+        }
+    }
+
+    fn set_protocol(&mut self, protocol: NitsProtocol) {
+        let mut settings = self.settings.borrow_mut();
+        settings.command_type_names = protocol.command_type_names;
+        settings.payload_schemas = protocol.payload_schemas;
+    }
+
+    // 読み込んだ定義を今の定義に足す。食い違うものがあれば確認してから足す
+    fn import_protocol(&mut self, imported: NitsProtocol) {
+        if self.protocol().conflicts(&imported).is_empty() {
+            let mut protocol = self.protocol();
+            protocol.merge(imported, false);
+            self.set_protocol(protocol);
+        } else {
+            self.pending_protocol = Some(imported);
+        }
+    }
+
+    fn protocol_conflict_dialog(&mut self, ctx: &Context) {
+        let Some(imported) = &self.pending_protocol else {
+            return;
+        };
+        let conflicts = self.protocol().conflicts(imported);
+        let mut choice = None;
+        egui::Window::new("Protocol conflicts")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("The imported protocol defines these command types differently:");
+                for command_type in &conflicts {
+                    ui.label(self.values.command_type_label(*command_type));
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Use imported").clicked() {
+                        choice = Some(Some(true));
+                    }
+                    if ui.button("Keep existing").clicked() {
+                        choice = Some(Some(false));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        choice = Some(None);
+                    }
+                });
+            });
+        if let Some(choice) = choice {
+            if let (Some(overwrite), Some(imported)) = (choice, self.pending_protocol.take()) {
+                let mut protocol = self.protocol();
+                protocol.merge(imported, overwrite);
+                self.set_protocol(protocol);
+            }
+            self.pending_protocol = None;
+        }
+    }
+
+    fn load_csv(&mut self, path: &Path, replace: bool) {
+        if replace {
+            self.values.clear();
+            self.paused_data.clear();
+        }
+        let zero_fill = self.settings.borrow().csv_zero_fill;
+        if let Err(e) = self.values.load_csv(path, zero_fill) {
+            self.file_error = Some(format!("Failed to load CSV: {}", e));
+        }
+    }
+
+    fn open_csv_dialog(&mut self, ctx: &Context) {
+        let Some(path) = self.pending_csv.clone() else {
+            return;
+        };
+        egui::Window::new("Open CSV")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(format!("Open {}", path.display()));
+                ui.horizontal(|ui| {
+                    if ui.button("Replace all").clicked() {
+                        self.load_csv(&path, true);
+                        self.pending_csv = None;
+                    }
+                    if ui.button("Append").clicked() {
+                        self.load_csv(&path, false);
+                        self.pending_csv = None;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.pending_csv = None;
+                    }
+                });
+            });
+    }
+
+    // 書き出す順に並べ替えたり外したりする
+    #[cfg(not(target_arch = "wasm32"))]
+    fn selected_keys_ui(&mut self, ui: &mut egui::Ui) {
+        let mut swap = None;
+        let mut remove = None;
+        let len = self.selected_keys.len();
+        for (i, key) in self.selected_keys.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                    swap = Some(i - 1);
+                }
+                if ui
+                    .add_enabled(i + 1 < len, egui::Button::new("⏷"))
+                    .clicked()
+                {
+                    swap = Some(i);
+                }
+                if ui.button("✕").clicked() {
+                    remove = Some(i);
+                }
+                ui.label(key);
+            });
+        }
+        if let Some(i) = swap {
+            self.selected_keys.swap(i, i + 1);
+        }
+        if let Some(i) = remove {
+            self.selected_keys.remove(i);
+        }
+    }
+
+    fn key_mapping_ui(&mut self, ui: &mut egui::Ui) {
+        let mut settings = self.settings.borrow_mut();
+        ui.checkbox(&mut settings.flatten_nested, "Flatten nested objects (a.b)");
+        ui.separator();
+        let mut remove = None;
+        for (from, to) in &settings.key_renames {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} → {}", from, to));
+                if ui.button("Remove").clicked() {
+                    remove = Some(from.clone());
+                }
+            });
+        }
+        if let Some(from) = remove {
+            settings.key_renames.remove(&from);
+        }
+        let (from, to) = &mut self.key_rename_input;
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(from).hint_text("Received key"));
+            ui.label("→");
+            ui.add(egui::TextEdit::singleline(to).hint_text("Display key"));
+        });
+        if ui
+            .add_enabled(!from.is_empty() && !to.is_empty(), egui::Button::new("Add"))
+            .clicked()
+        {
+            let (from, to) = std::mem::take(&mut self.key_rename_input);
+            settings.key_renames.insert(from, to);
+        }
+    }
+
+    // JSON として読めることを確かめてからテキストのまま送る
+    fn send_command(&mut self) -> Result<(), String> {
+        let value = serde_json::from_str::<serde_json::Value>(&self.command_text)
+            .map_err(|e| format!("Invalid JSON: {}", e))?;
+        if !value.is_object() {
+            return Err("Command must be a JSON object".to_owned());
+        }
+        match (&mut self.ws, self.connection_state) {
+            (Some((sender, _)), ConnectionState::Connected) => {
+                sender.send(WsMessage::Text(self.command_text.clone()));
+                Ok(())
+            }
+            _ => Err("Not connected".to_owned()),
+        }
+    }
+
+    fn command_window(&mut self, ctx: &Context) {
+        let mut open = self.command_open;
+        egui::Window::new("Command")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.command_text)
+                        .code_editor()
+                        .hint_text("{\"setpoint\": 1.0}")
+                        .desired_rows(4),
+                );
+                ui.horizontal(|ui| {
+                    if ui.button("Send").clicked() {
+                        self.command_error = self.send_command().err();
+                    }
+                    if let Some(error) = &self.command_error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
+                });
+            });
+        self.command_open = open;
+    }
+
+    fn computed_keys_ui(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        for (key, text) in &self.settings.borrow().computed_keys {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} = {}", key, text));
+                if ui.button("Remove").clicked() {
+                    remove = Some(key.clone());
+                }
+            });
+        }
+        if let Some(key) = remove {
+            self.settings.borrow_mut().computed_keys.remove(&key);
+            self.values.update_computed_keys();
+        }
+        ui.separator();
+        let (key, text) = &mut self.computed_key_input;
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(key).hint_text("Name"));
+            ui.label("=");
+            ui.add(egui::TextEdit::singleline(text).hint_text("\"Key A\" - \"Key B\""));
+        });
+        if ui
+            .add_enabled(!key.is_empty(), egui::Button::new("Add"))
+            .clicked()
+        {
+            match Expr::parse(text) {
+                Ok(_) => {
+                    let (key, text) = std::mem::take(&mut self.computed_key_input);
+                    self.settings.borrow_mut().computed_keys.insert(key, text);
+                    self.values.update_computed_keys();
+                    self.computed_key_error = None;
+                }
+                Err(e) => self.computed_key_error = Some(e),
+            }
+        }
+        if let Some(error) = &self.computed_key_error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+    }
+
+    fn file_error_dialog(&mut self, ctx: &Context) {
+        let Some(error) = &self.file_error else {
+            return;
+        };
+        let mut close = false;
+        egui::Window::new("File error")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(error);
+                close = ui.button("OK").clicked();
+            });
+        if close {
+            self.file_error = None;
+        }
+    }
+}
+
+fn validate_server_url(server: &str) -> Result<(), String> {
+    let url = url::Url::parse(server).map_err(|e| format!("Invalid URL: {}", e))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(format!(
+            "Unsupported scheme \"{}\": use ws:// or wss://",
+            url.scheme()
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("Missing host".into());
+    }
+    Ok(())
+}
+
+// メインのキー一覧の行 (グループの見出しかキー)
+enum MainTableRow<'a> {
+    // 接頭辞とそのグループのキーの数
+    Group(&'a str, usize),
+    Key(&'a String),
+}
+
+// ソート済みの keys を separator の前までが同じものごとにまとめる
+// 同じ接頭辞のキーが1つしかなければまとめずにそのまま並べる
+fn group_keys<'a>(
+    keys: &[&'a String],
+    separator: Option<char>,
+    expanded_groups: &BTreeSet<String>,
+) -> Vec<MainTableRow<'a>> {
+    let prefix = |key: &'a String| separator.and_then(|c| key.split_once(c)).map(|(p, _)| p);
+    let mut rows = vec![];
+    let mut i = 0;
+    while i < keys.len() {
+        let group = prefix(keys[i]);
+        let len = keys[i..]
+            .iter()
+            .take_while(|k| group.is_some() && prefix(k) == group)
+            .count()
+            .max(1);
+        match group {
+            Some(group) if len > 1 => {
+                rows.push(MainTableRow::Group(group, len));
+                if expanded_groups.contains(group) {
+                    rows.extend(keys[i..i + len].iter().map(|k| MainTableRow::Key(k)));
+                }
+            }
+            _ => rows.push(MainTableRow::Key(keys[i])),
         }
+        i += len;
     }
+    rows
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct App {
-    id: u32,
-    server: String,
-    #[serde(skip, default)]
-    ws: Option<(WsSender, WsReceiver)>,
-    values: Values,
-    settings: Rc<RefCell<Settings>>,
-    windows: Vec<(Window, bool)>,
-    #[serde(skip, default)]
-    open_dialog: Option<FileDialog>,
-    #[serde(skip, default)]
-    save_dialog: Option<FileDialog>,
+// 「データ無し」として扱う値の設定
+fn missing_value_ui(ui: &mut egui::Ui, missing_value: &mut Option<f64>) {
+    ui.horizontal(|ui| {
+        let mut enabled = missing_value.is_some();
+        if ui.checkbox(&mut enabled, "Treat as missing:").changed() {
+            *missing_value = enabled.then_some(-1.0);
+        }
+        if let Some(v) = missing_value {
+            ui.add(egui::DragValue::new(v));
+        }
+    });
 }
 
-impl App {
-    pub fn new(cc: &eframe::CreationContext) -> Self {
-        if let Some(storage) = cc.storage {
-            let app_op: Option<App> = eframe::get_value(storage, eframe::APP_KEY);
-            if let Some(mut app) = app_op {
-                app.values.set_settings(Rc::clone(&app.settings));
-                return app;
-            }
+#[cfg(not(target_arch = "wasm32"))]
+fn record_rotation_ui(ui: &mut egui::Ui, rotation: &mut crate::settings::RecordRotation) {
+    ui.label("Start a new file every");
+    ui.horizontal(|ui| {
+        let mut enabled = rotation.max_mib.is_some();
+        if ui.checkbox(&mut enabled, "Size").changed() {
+            rotation.max_mib = enabled.then_some(100);
         }
-        #[cfg(target_arch = "wasm32")]
-        let server = {
-            let location = &cc.integration_info.web_info.location;
-            format!("ws://{}/socket", location.host)
-        };
-        #[cfg(not(target_arch = "wasm32"))]
-        let server = "ws://127.0.0.1:8080/socket".into();
-        let settings = Rc::new(RefCell::new(Settings::default()));
-        Self {
-            id: 0,
-            server,
-            ws: None,
-            values: Values::new(Rc::clone(&settings)),
-            settings,
-            windows: vec![],
-            open_dialog: None,
-            save_dialog: None,
+        if let Some(mib) = rotation.max_mib.as_mut() {
+            ui.add(egui::DragValue::new(mib).range(1..=65536).suffix(" MiB"));
+        }
+    });
+    ui.horizontal(|ui| {
+        let mut enabled = rotation.max_minutes.is_some();
+        if ui.checkbox(&mut enabled, "Duration").changed() {
+            rotation.max_minutes = enabled.then_some(60);
+        }
+        if let Some(minutes) = rotation.max_minutes.as_mut() {
+            ui.add(
+                egui::DragValue::new(minutes)
+                    .range(1..=24 * 60)
+                    .suffix(" min"),
+            );
+        }
+    });
+}
+
+fn line_colors_ui(ui: &mut egui::Ui, colors: &mut LineColors) {
+    ui.horizontal(|ui| {
+        let mut enabled = colors.accent.is_some();
+        if ui.checkbox(&mut enabled, "Accent").changed() {
+            colors.accent = enabled.then_some(colors.color(0));
+        }
+        if let Some(accent) = colors.accent.as_mut() {
+            ui.color_edit_button_srgba(accent);
         }
+    });
+    ui.label(
+        egui::RichText::new("The accent is the first line's color unless a cycle is set").weak(),
+    );
+    ui.separator();
+    ui.label("Color cycle");
+    let mut remove = None;
+    for (i, color) in colors.cycle.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(format!("{}", i + 1));
+            ui.color_edit_button_srgba(color);
+            if ui.small_button("Remove").clicked() {
+                remove = Some(i);
+            }
+        });
     }
+    if let Some(i) = remove {
+        colors.cycle.remove(i);
+    }
+    ui.horizontal(|ui| {
+        if ui.button("Add").clicked() {
+            // 自動の色の並びの続きを足す
+            let auto = LineColors {
+                accent: colors.accent,
+                cycle: vec![],
+            };
+            let color = auto.color(colors.cycle.len());
+            colors.cycle.push(color);
+        }
+        if ui
+            .add_enabled(!colors.cycle.is_empty(), egui::Button::new("Reset"))
+            .clicked()
+        {
+            colors.cycle.clear();
+        }
+    });
 }
 
 impl eframe::App for App {
@@ -86,37 +1263,71 @@ impl eframe::App for App {
     }
 
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
-        if let Some((_, rx)) = self.ws.as_ref() {
-            while let Some(e) = rx.try_recv() {
-                match e {
-                    ewebsock::WsEvent::Opened => {}
-                    ewebsock::WsEvent::Message(WsMessage::Text(m)) => {
-                        match serde_json::from_str::<HashMap<String, Vec<f32>>>(&m) {
-                            Ok(v) => {
-                                self.values.add_data(v);
-                            }
-                            Err(e) => {
-                                log::error!("failed to parse: {}", e);
-                            }
+        while let Some(e) = self.ws.as_ref().and_then(|(_, rx)| rx.try_recv()) {
+            match e {
+                ewebsock::WsEvent::Opened => {
+                    self.connection_state = ConnectionState::Connected;
+                    self.connect_error = None;
+                    self.log_connection(ctx, "Connected".to_owned(), false);
+                }
+                ewebsock::WsEvent::Message(WsMessage::Text(m)) => {
+                    self.reconnect_delay = RECONNECT_DELAY_MIN;
+                    let flatten = self.settings.borrow().flatten_nested;
+                    match wire_format::decode_json(&m, flatten) {
+                        Ok(v) => {
+                            let v =
+                                wire_format::rename_keys(v, &self.settings.borrow().key_renames);
+                            self.receive(v, ctx.input(|i| i.time));
                         }
+                        Err(e) => {
+                            log::error!("failed to parse: {}", e);
+                        }
+                    }
+                }
+                ewebsock::WsEvent::Message(WsMessage::Binary(b)) => {
+                    if self.settings.borrow().wire_format != WireFormat::BinaryV1 {
+                        continue;
                     }
-                    ewebsock::WsEvent::Message(_) => {}
-                    ewebsock::WsEvent::Error(e) => log::error!("{}", e),
-                    ewebsock::WsEvent::Closed => {
-                        let ctx = ctx.clone();
-                        let wakeup = move || ctx.request_repaint();
-                        self.ws =
-                            ewebsock::connect_with_wakeup(&self.server, Default::default(), wakeup)
-                                .map_err(|e| log::error!("failed to init websocket {}", e))
-                                .ok();
-                        break;
+                    self.reconnect_delay = RECONNECT_DELAY_MIN;
+                    match wire_format::decode_frame(&b) {
+                        Some(v) => {
+                            let v =
+                                wire_format::rename_keys(v, &self.settings.borrow().key_renames);
+                            self.receive(v, ctx.input(|i| i.time));
+                        }
+                        None => {
+                            log::error!("failed to decode binary frame ({} bytes)", b.len());
+                        }
                     }
                 }
+                ewebsock::WsEvent::Message(_) => {}
+                // エラーの後は接続が閉じられるので、どちらでも再接続を待つ
+                ewebsock::WsEvent::Error(e) => {
+                    log::error!("{}", e);
+                    self.log_connection(ctx, e.clone(), true);
+                    self.connect_error = Some(e);
+                    self.schedule_reconnect(ctx);
+                    break;
+                }
+                ewebsock::WsEvent::Closed => {
+                    self.log_connection(ctx, "Connection closed".to_owned(), false);
+                    self.schedule_reconnect(ctx);
+                    break;
+                }
+            }
+        }
+        self.poll_reconnect(ctx);
+        if self.demo.is_some() {
+            let time = ctx.input(|i| i.time);
+            if let Some(data) = self.demo.as_mut().and_then(|d| d.update(time)) {
+                self.receive(data, time);
             }
+            ctx.request_repaint();
         }
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 egui::widgets::global_theme_preference_switch(ui);
+                self.settings.borrow_mut().theme = ctx.options(|o| o.theme_preference);
                 ui.separator();
                 ui.menu_button("File", |ui| {
                     #[cfg(not(target_arch = "wasm32"))]
@@ -133,6 +1344,57 @@ impl eframe::App for App {
                             fd.open();
                             self.save_dialog = Some(fd);
                         }
+                        ui.add_enabled_ui(!self.selected_keys.is_empty(), |ui| {
+                            ui.menu_button("Save selected as CSV", |ui| {
+                                self.selected_keys_ui(ui);
+                                ui.separator();
+                                if ui.button("Save").clicked() {
+                                    let mut fd = FileDialog::save_file(None)
+                                        .default_filename("selected.csv")
+                                        .title("Save selected as CSV");
+                                    fd.open();
+                                    self.save_selected_dialog = Some(fd);
+                                    ui.close_menu();
+                                }
+                            });
+                        });
+                        if self.recorder.is_some() {
+                            if ui.button("Stop recording").clicked() {
+                                self.stop_recording();
+                            }
+                        } else if ui.button("Start recording").clicked() {
+                            let mut fd = FileDialog::save_file(None)
+                                .default_filename("recording.jsonl")
+                                .title("Start recording");
+                            fd.open();
+                            self.record_dialog = Some(fd);
+                        }
+                        ui.separator();
+                        if ui.button("Save layout").clicked() {
+                            let mut fd = FileDialog::save_file(None)
+                                .default_filename("layout.json")
+                                .title("Save layout");
+                            fd.open();
+                            self.save_layout_dialog = Some(fd);
+                        }
+                        if ui.button("Load layout").clicked() {
+                            let mut fd = FileDialog::open_file(None).title("Load layout");
+                            fd.open();
+                            self.load_layout_dialog = Some(fd);
+                        }
+                        if ui.button("Save as template").clicked() {
+                            let mut fd = FileDialog::save_file(None)
+                                .default_filename("template.toml")
+                                .title("Save as template");
+                            fd.open();
+                            self.save_template_dialog = Some(fd);
+                        }
+                        if ui.button("Add from template").clicked() {
+                            let mut fd = FileDialog::open_file(None).title("Add from template");
+                            fd.open();
+                            self.apply_template_dialog = Some(fd);
+                        }
+                        ui.separator();
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
@@ -140,34 +1402,324 @@ impl eframe::App for App {
                 });
                 ui.menu_button("Settings", |ui| {
                     ui.menu_button("Retention period", |ui| {
-                        for (label, len) in [
-                            ("10sec", 60 * 10),
-                            ("1min", 60 * 60),
-                            ("5min", 60 * 60 * 5),
-                            ("10min", 60 * 60 * 10),
-                            ("15min", 60 * 60 * 15),
-                            ("30min", 60 * 60 * 30),
+                        let current = self.settings.borrow().retention_period;
+                        let sample_rate = self.settings.borrow().sample_rate_hz;
+                        for (label, seconds) in [
+                            ("10sec", 10),
+                            ("1min", 60),
+                            ("5min", 60 * 5),
+                            ("10min", 60 * 10),
+                            ("15min", 60 * 15),
+                            ("30min", 60 * 30),
                         ] {
+                            let len = seconds * sample_rate;
+                            if ui.radio(current == len, label).clicked() {
+                                self.retention_input = None;
+                                self.request_retention_period(len);
+                                ui.close_menu();
+                            }
+                        }
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            let seconds = self
+                                .retention_input
+                                .get_or_insert(current.div_ceil(sample_rate));
+                            ui.add(
+                                egui::DragValue::new(seconds)
+                                    .range(1..=MAX_RETENTION_SECONDS)
+                                    .suffix(" s"),
+                            );
+                            let len = *seconds * sample_rate;
                             if ui
-                                .radio_value(
-                                    &mut self.settings.borrow_mut().retention_period,
-                                    len,
-                                    label,
-                                )
+                                .add_enabled(len != current, egui::Button::new("Apply"))
                                 .clicked()
                             {
-                                self.values.set_max_len();
+                                self.retention_input = None;
+                                self.request_retention_period(len);
+                                ui.close_menu();
+                            }
+                        });
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Sample rate");
+                        let changed = ui
+                            .add(
+                                egui::DragValue::new(
+                                    &mut self.settings.borrow_mut().sample_rate_hz,
+                                )
+                                .range(1..=240)
+                                .suffix(" Hz"),
+                            )
+                            .changed();
+                        // 保持期間の上限が変わるので詰め直す
+                        if changed {
+                            self.retention_input = None;
+                            self.values.set_max_len();
+                        }
+                    });
+                    ui.menu_button("Number format", |ui| {
+                        let number_format = &mut self.settings.borrow_mut().number_format;
+                        ui.label("Decimal separator");
+                        for (label, c) in [("Period (1.5)", '.'), ("Comma (1,5)", ',')] {
+                            ui.radio_value(&mut number_format.decimal_separator, c, label);
+                        }
+                        ui.separator();
+                        ui.checkbox(&mut number_format.digit_grouping, "Digit grouping");
+                        ui.add_enabled_ui(number_format.digit_grouping, |ui| {
+                            for (label, c) in [
+                                ("Comma (1,000)", ','),
+                                ("Period (1.000)", '.'),
+                                ("Space (1 000)", ' '),
+                                ("Apostrophe (1'000)", '\''),
+                            ] {
+                                ui.radio_value(&mut number_format.grouping_separator, c, label);
+                            }
+                        });
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            let mut fixed = number_format.decimal_places.is_some();
+                            if ui.checkbox(&mut fixed, "Decimal places").changed() {
+                                number_format.decimal_places = fixed.then_some(3);
+                            }
+                            if let Some(places) = number_format.decimal_places.as_mut() {
+                                ui.add(egui::DragValue::new(places).range(0..=10));
+                            }
+                        });
+                    });
+                    ui.menu_button("Wire format", |ui| {
+                        let wire_format = &mut self.settings.borrow_mut().wire_format;
+                        for (label, format) in [
+                            ("JSON text", WireFormat::JsonText),
+                            ("Binary v1", WireFormat::BinaryV1),
+                        ] {
+                            if ui.radio_value(wire_format, format, label).clicked() {
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    ui.menu_button("Key mapping", |ui| self.key_mapping_ui(ui));
+                    ui.menu_button("Computed keys", |ui| self.computed_keys_ui(ui));
+                    ui.menu_button("Sample alignment", |ui| {
+                        let sample_alignment = &mut self.settings.borrow_mut().sample_alignment;
+                        for (label, alignment) in [
+                            ("Align end", SampleAlignment::End),
+                            ("Align start", SampleAlignment::Start),
+                            ("By timestamp", SampleAlignment::Timestamp),
+                        ] {
+                            if ui.radio_value(sample_alignment, alignment, label).clicked() {
                                 ui.close_menu();
                             }
                         }
                     });
+                    ui.horizontal(|ui| {
+                        ui.label("Memory budget");
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.borrow_mut().memory_budget)
+                                .range(1..=65536)
+                                .suffix(" MiB"),
+                        );
+                    });
+                    ui.label(
+                        egui::RichText::new(format!(
+                            "Currently about {:.1} MiB",
+                            self.values.estimated_memory_bytes() as f64 / 1024.0 / 1024.0
+                        ))
+                        .weak(),
+                    );
+                    ui.horizontal(|ui| {
+                        ui.label("Max keys");
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.borrow_mut().max_keys)
+                                .range(1..=1_000_000),
+                        );
+                    });
+                    let rejected = self.values.rejected_count();
+                    let key_text = format!("{} keys in use", self.values.key_count());
+                    if rejected > 0 {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            format!("{}, new keys dropped in {} batches", key_text, rejected),
+                        );
+                    } else {
+                        ui.label(egui::RichText::new(key_text).weak());
+                    }
                     ui.checkbox(
                         &mut self.settings.borrow_mut().keep_values,
-                        "Kepp values on quit",
-                    )
+                        "Keep values on exit",
+                    );
+                    ui.checkbox(
+                        &mut self.settings.borrow_mut().auto_connect,
+                        "Reconnect on startup",
+                    );
+                    ui.checkbox(
+                        &mut self.settings.borrow_mut().csv_zero_fill,
+                        "Zero-fill invalid CSV cells",
+                    );
+                    ui.menu_button("CSV format", |ui| {
+                        let csv_format = &mut self.settings.borrow_mut().csv_format;
+                        ui.label("Delimiter");
+                        for (label, delimiter) in [
+                            ("Auto", None),
+                            ("Comma (,)", Some(',')),
+                            ("Semicolon (;)", Some(';')),
+                            ("Tab", Some('\t')),
+                        ] {
+                            ui.radio_value(&mut csv_format.delimiter, delimiter, label);
+                        }
+                        ui.separator();
+                        ui.label("Decimal separator");
+                        for (label, c) in [("Period (1.5)", '.'), ("Comma (1,5)", ',')] {
+                            ui.radio_value(&mut csv_format.decimal_separator, c, label);
+                        }
+                    });
+                    ui.checkbox(
+                        &mut self.settings.borrow_mut().auto_scroll,
+                        "Auto-scroll to latest",
+                    );
+                    ui.checkbox(
+                        &mut self.settings.borrow_mut().collapse_constants,
+                        "Collapse constant channels",
+                    );
+                    ui.horizontal(|ui| {
+                        let separator = &mut self.settings.borrow_mut().key_group_separator;
+                        let mut grouped = separator.is_some();
+                        if ui.checkbox(&mut grouped, "Group keys by prefix").changed() {
+                            *separator = grouped.then_some(' ');
+                        }
+                        if let Some(c) = separator {
+                            let mut text = c.to_string();
+                            ui.label("separator");
+                            if ui
+                                .add(egui::TextEdit::singleline(&mut text).desired_width(20.0))
+                                .changed()
+                            {
+                                if let Some(new) = text.chars().last() {
+                                    *c = new;
+                                }
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("No data warning after");
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.borrow_mut().stale_timeout)
+                                .range(0.1..=3600.0)
+                                .suffix(" s"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Tick counter key");
+                        let mut key = self
+                            .settings
+                            .borrow()
+                            .tick_counter_key
+                            .clone()
+                            .unwrap_or_default();
+                        if ui
+                            .add(
+                                egui::TextEdit::singleline(&mut key)
+                                    .hint_text("None")
+                                    .desired_width(100.0),
+                            )
+                            .changed()
+                        {
+                            self.settings.borrow_mut().tick_counter_key =
+                                (!key.is_empty()).then_some(key);
+                            self.values.clear_gaps();
+                        }
+                    });
+                    missing_value_ui(ui, &mut self.settings.borrow_mut().missing_value);
+                    ui.horizontal(|ui| {
+                        ui.label("NITS payload bits");
+                        let mut payload_bits = self.values.nits_payload_bits();
+                        if ui
+                            .add(egui::DragValue::new(&mut payload_bits).range(PAYLOAD_BITS_RANGE))
+                            .on_hover_text("The remaining upper bits are the command type")
+                            .changed()
+                        {
+                            self.values.set_nits_payload_bits(payload_bits);
+                        }
+                    });
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.menu_button("Recording", |ui| {
+                        record_rotation_ui(ui, &mut self.settings.borrow_mut().record_rotation);
+                    });
+                    ui.menu_button("Line colors", |ui| {
+                        line_colors_ui(ui, &mut self.settings.borrow_mut().line_colors);
+                    });
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.horizontal(|ui| {
+                        if ui.button("Load NITS command names").clicked() {
+                            let mut fd =
+                                FileDialog::open_file(None).title("Load NITS command names");
+                            fd.open();
+                            self.command_type_names_dialog = Some(fd);
+                            ui.close_menu();
+                        }
+                        let mut settings = self.settings.borrow_mut();
+                        if !settings.command_type_names.is_empty() && ui.button("Clear").clicked() {
+                            settings.command_type_names.clear();
+                        }
+                    });
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.horizontal(|ui| {
+                        if ui.button("Load NITS payload schemas").clicked() {
+                            let mut fd =
+                                FileDialog::open_file(None).title("Load NITS payload schemas");
+                            fd.open();
+                            self.payload_schemas_dialog = Some(fd);
+                            ui.close_menu();
+                        }
+                        let mut settings = self.settings.borrow_mut();
+                        if !settings.payload_schemas.is_empty() && ui.button("Clear").clicked() {
+                            settings.payload_schemas.clear();
+                        }
+                    });
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.horizontal(|ui| {
+                        if ui.button("Import NITS protocol").clicked() {
+                            let mut fd = FileDialog::open_file(None).title("Import NITS protocol");
+                            fd.open();
+                            self.import_protocol_dialog = Some(fd);
+                            ui.close_menu();
+                        }
+                        if ui.button("Export").clicked() {
+                            let mut fd = FileDialog::save_file(None)
+                                .default_filename("nits_protocol.json")
+                                .title("Export NITS protocol");
+                            fd.open();
+                            self.export_protocol_dialog = Some(fd);
+                            ui.close_menu();
+                        }
+                    });
                 });
-                if ui.button("Reset").clicked() {
-                    self.values = Values::new(Rc::clone(&self.settings));
+                if ui.button("Clear data").clicked() {
+                    self.values.clear();
+                    self.paused_data.clear();
+                }
+                if ui.button("Reset layout").clicked() {
+                    self.windows.clear();
+                }
+                let mut paused = self.paused;
+                if ui
+                    .toggle_value(&mut paused, "Pause")
+                    .on_hover_text(format!("{} messages buffered", self.paused_data.len()))
+                    .clicked()
+                {
+                    if paused {
+                        self.paused = true;
+                    } else {
+                        self.resume();
+                    }
+                }
+                if let Some(recorder) = &self.recorder {
+                    ui.colored_label(egui::Color32::RED, "● REC")
+                        .on_hover_text(format!(
+                            "{}\n{:.1} MiB, {} ticks",
+                            recorder.current_path().display(),
+                            recorder.bytes() as f64 / 1024.0 / 1024.0,
+                            recorder.ticks()
+                        ));
                 }
                 ui.separator();
                 if ui.button("XY Graph").clicked() {
@@ -187,6 +1739,22 @@ impl eframe::App for App {
                     ));
                     self.id += 1;
                 }
+                if ui.button("Overview").clicked() {
+                    self.windows.push((
+                        Window::Overview(Box::new(OverviewWindow::new(format!(
+                            "overview_{}",
+                            self.id
+                        )))),
+                        true,
+                    ));
+                    self.id += 1;
+                }
+                if ui.button("Measurements").clicked() {
+                    self.measurements_open = true;
+                }
+                if ui.button("Command").clicked() {
+                    self.command_open = true;
+                }
                 if ui.button("NITS Timeline").clicked() {
                     self.windows.push((
                         Window::NitsTimeline(Box::new(NitsTimelineWindow::new(format!(
@@ -200,37 +1768,163 @@ impl eframe::App for App {
             });
         });
 
+        if !self.windows.is_empty() {
+            egui::TopBottomPanel::top("window_list").show(ctx, |ui| {
+                egui::ScrollArea::horizontal().show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        let top = ctx.top_layer_id().map(|l| l.id);
+                        for (window, _) in &self.windows {
+                            if ui
+                                .selectable_label(top == Some(window.id()), window.title())
+                                .clicked()
+                            {
+                                window.bring_to_front(ctx);
+                            }
+                        }
+                    });
+                });
+            });
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
-                ui.text_edit_singleline(&mut self.server);
-                if self.ws.is_none() {
+                self.connection_status(ui);
+                if ui.text_edit_singleline(&mut self.server).changed() {
+                    self.connect_error = None;
+                }
+                if self.ws.is_none() && self.demo.is_none() {
                     if ui.button("connect").clicked() {
-                        let ctx = ctx.clone();
-                        let wakeup = move || ctx.request_repaint();
-                        self.ws =
-                            ewebsock::connect_with_wakeup(&self.server, Default::default(), wakeup)
-                                .map_err(|e| log::error!("failed to init websocket {}", e))
-                                .ok();
+                        self.source = Some(SourceConfig::WebSocket);
+                        self.start_source(ctx);
+                    }
+                    if ui.button("demo").clicked() {
+                        self.source = Some(SourceConfig::Demo);
+                        self.start_source(ctx);
                     }
                 } else if ui.button("disconnect").clicked() {
+                    self.source = None;
                     self.ws = None;
+                    self.reconnect_at = None;
+                    self.reconnect_delay = RECONNECT_DELAY_MIN;
+                    self.demo = None;
+                    self.connection_state = ConnectionState::Disconnected;
+                }
+                if let Some(reconnect_at) = self.reconnect_at {
+                    let remaining = (reconnect_at - ctx.input(|i| i.time)).max(0.0);
+                    ui.label(format!("connecting in {:.0}s…", remaining.ceil()));
+                }
+                if let Some(e) = &self.connect_error {
+                    ui.colored_label(ui.visuals().error_fg_color, e);
                 }
             });
+            self.feed_status(ui);
+            self.connection_log_ui(ui);
+            ui.label(
+                egui::RichText::new(format!(
+                    "{} channels, {} samples, {} NITS ticks",
+                    self.values.keys().count(),
+                    self.values.sample_count(),
+                    self.values.nits_ticks().len()
+                ))
+                .weak(),
+            )
+            .on_hover_text(format!(
+                "About {} MiB when the retention period is filled",
+                self.values
+                    .approx_memory_bytes(self.settings.borrow().max_len())
+                    / 1024
+                    / 1024
+            ));
             ui.separator();
             self.table(ui);
         });
 
+        self.retention_period_dialog(ctx);
+        self.open_csv_dialog(ctx);
+        self.file_error_dialog(ctx);
+
         for graph in &mut self.windows {
             graph.0.show(ctx, &mut graph.1, &self.values);
         }
         self.windows.retain(|g| g.1);
+        self.plot_nits_commands();
+        self.capture_measurements();
+        self.sync_marked_time();
+        self.collect_file_errors();
+        self.open_overview_graphs();
+
+        self.command_window(ctx);
+
+        if self.measurements.show(ctx, &mut self.measurements_open) {
+            let mut fd = FileDialog::save_file(None)
+                .default_filename("measurements.csv")
+                .title("Export measurements");
+            fd.open();
+            self.measurements_dialog = Some(fd);
+        }
+
+        if let Some(command_type_names_dialog) = self.command_type_names_dialog.as_mut() {
+            if command_type_names_dialog.show(ctx).selected() {
+                if let Some(path) = command_type_names_dialog.path() {
+                    match load_command_type_map(path) {
+                        Ok(names) => self.settings.borrow_mut().command_type_names = names,
+                        Err(e) => {
+                            self.file_error = Some(format!("Failed to load command names: {}", e))
+                        }
+                    }
+                }
+                self.command_type_names_dialog = None;
+            }
+        }
+
+        if let Some(payload_schemas_dialog) = self.payload_schemas_dialog.as_mut() {
+            if payload_schemas_dialog.show(ctx).selected() {
+                if let Some(path) = payload_schemas_dialog.path() {
+                    match load_command_type_map(path) {
+                        Ok(schemas) => {
+                            self.settings.borrow_mut().payload_schemas = schemas
+                                .into_iter()
+                                .map(|(k, v)| (NitsCommandType::new(k), v))
+                                .collect();
+                        }
+                        Err(e) => {
+                            self.file_error = Some(format!("Failed to load payload schemas: {}", e))
+                        }
+                    }
+                }
+                self.payload_schemas_dialog = None;
+            }
+        }
+
+        if let Some(export_protocol_dialog) = self.export_protocol_dialog.as_mut() {
+            if export_protocol_dialog.show(ctx).selected() {
+                if let Some(path) = export_protocol_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.protocol().save(&path) {
+                        self.file_error = Some(format!("Failed to export protocol: {}", e));
+                    }
+                }
+                self.export_protocol_dialog = None;
+            }
+        }
+
+        if let Some(import_protocol_dialog) = self.import_protocol_dialog.as_mut() {
+            if import_protocol_dialog.show(ctx).selected() {
+                if let Some(path) = import_protocol_dialog.path().map(Path::to_path_buf) {
+                    match NitsProtocol::load(&path) {
+                        Ok(protocol) => self.import_protocol(protocol),
+                        Err(e) => {
+                            self.file_error = Some(format!("Failed to import protocol: {}", e))
+                        }
+                    }
+                }
+                self.import_protocol_dialog = None;
+            }
+        }
+        self.protocol_conflict_dialog(ctx);
 
         if let Some(open_dialog) = self.open_dialog.as_mut() {
             if open_dialog.show(ctx).selected() {
-                if let Some(path) = open_dialog.path() {
-                    self.values = Values::new(Rc::clone(&self.settings));
-                    self.values.load_csv(path);
-                }
+                self.pending_csv = open_dialog.path().map(Path::to_path_buf);
                 self.open_dialog = None;
             }
         }
@@ -238,27 +1932,130 @@ impl eframe::App for App {
         if let Some(save_dialog) = self.save_dialog.as_mut() {
             if save_dialog.show(ctx).selected() {
                 if let Some(path) = save_dialog.path() {
-                    let _ = self.values.save_csv(path, self.values.keys());
+                    if let Err(e) = self.values.save_csv(path, self.values.keys()) {
+                        self.file_error = Some(format!("Failed to save CSV: {}", e));
+                    }
                 }
                 self.save_dialog = None;
             }
         }
+
+        if let Some(save_selected_dialog) = self.save_selected_dialog.as_mut() {
+            if save_selected_dialog.show(ctx).selected() {
+                if let Some(path) = save_selected_dialog.path() {
+                    // 今は無いキーは save_csv が飛ばす
+                    if let Err(e) = self.values.save_csv(path, self.selected_keys.iter()) {
+                        self.file_error = Some(format!("Failed to save CSV: {}", e));
+                    }
+                }
+                self.save_selected_dialog = None;
+            }
+        }
+
+        if let Some(measurements_dialog) = self.measurements_dialog.as_mut() {
+            if measurements_dialog.show(ctx).selected() {
+                if let Some(path) = measurements_dialog.path() {
+                    if let Err(e) = self.measurements.save_csv(path) {
+                        self.file_error = Some(format!("Failed to export measurements: {}", e));
+                    }
+                }
+                self.measurements_dialog = None;
+            }
+        }
+
+        if let Some(record_dialog) = self.record_dialog.as_mut() {
+            if record_dialog.show(ctx).selected() {
+                if let Some(path) = record_dialog.path().map(Path::to_path_buf) {
+                    self.start_recording(&path);
+                }
+                self.record_dialog = None;
+            }
+        }
+
+        if let Some(save_layout_dialog) = self.save_layout_dialog.as_mut() {
+            if save_layout_dialog.show(ctx).selected() {
+                if let Some(path) = save_layout_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.save_layout(ctx, &path) {
+                        self.file_error = Some(format!("Failed to save layout: {}", e));
+                    }
+                }
+                self.save_layout_dialog = None;
+            }
+        }
+
+        if let Some(load_layout_dialog) = self.load_layout_dialog.as_mut() {
+            if load_layout_dialog.show(ctx).selected() {
+                if let Some(path) = load_layout_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.load_layout(ctx, &path) {
+                        self.file_error = Some(format!("Failed to load layout: {}", e));
+                    }
+                }
+                self.load_layout_dialog = None;
+            }
+        }
+
+        if let Some(save_template_dialog) = self.save_template_dialog.as_mut() {
+            if save_template_dialog.show(ctx).selected() {
+                if let Some(path) = save_template_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.save_template(ctx, &path) {
+                        self.file_error = Some(format!("Failed to save template: {}", e));
+                    }
+                }
+                self.save_template_dialog = None;
+            }
+        }
+
+        if let Some(apply_template_dialog) = self.apply_template_dialog.as_mut() {
+            if apply_template_dialog.show(ctx).selected() {
+                if let Some(path) = apply_template_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.apply_template(ctx, &path) {
+                        self.file_error = Some(format!("Failed to load template: {}", e));
+                    }
+                }
+                self.apply_template_dialog = None;
+            }
+        }
     }
 }
 
 impl App {
     fn table(&mut self, ui: &mut egui::Ui) {
-        let mut keys: Vec<_> = self.values.keys().collect();
+        let query = super::key_filter_ui(ui, Id::new("main_key_filter"));
+        let mut keys: Vec<_> = super::filter_keys(self.values.keys(), &query).collect();
         keys.sort();
+        if self.settings.borrow().collapse_constants {
+            let (constants, dynamic): (Vec<_>, Vec<_>) =
+                keys.into_iter().partition(|k| self.values.is_constant(k));
+            keys = dynamic;
+            if !constants.is_empty() {
+                self.constants(ui, &constants);
+            }
+        }
+        let separator = self.settings.borrow().key_group_separator;
+        let rows = if separator.is_some() {
+            group_keys(&keys, separator, &self.expanded_groups)
+        } else {
+            keys.iter().map(|k| MainTableRow::Key(k)).collect()
+        };
         use egui_extras::{Column, TableBuilder};
         let table = TableBuilder::new(ui)
             .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
             .column(Column::auto())
+            .column(Column::auto())
             .column(Column::exact(256.0))
-            .column(Column::auto());
+            .column(Column::auto())
+            .columns(
+                Column::auto().at_least(60.0),
+                if self.show_mean_stddev { 4 } else { 2 },
+            );
 
+        let mut stat_labels = vec!["Min", "Max"];
+        if self.show_mean_stddev {
+            stat_labels.extend(["Mean", "Std Dev"]);
+        }
         table
             .header(20.0, |mut header| {
+                header.col(|_| {});
                 header.col(|_| {});
                 header.col(|ui| {
                     ui.strong("Key");
@@ -266,11 +2063,70 @@ impl App {
                 header.col(|ui| {
                     ui.strong("Last Value");
                 });
+                for label in &stat_labels {
+                    header
+                        .col(|ui| {
+                            ui.strong(*label);
+                        })
+                        .1
+                        .context_menu(|ui| {
+                            if ui
+                                .checkbox(&mut self.show_mean_stddev, "Show mean and std dev")
+                                .clicked()
+                            {
+                                ui.close_menu();
+                            }
+                        });
+                }
             })
             .body(|body| {
-                body.rows(20.0, keys.len(), |mut row| {
-                    let index = row.index();
-                    let key = keys[index];
+                body.rows(20.0, rows.len(), |mut row| {
+                    let key = match &rows[row.index()] {
+                        MainTableRow::Group(prefix, count) => {
+                            let expanded = self.expanded_groups.contains(*prefix);
+                            row.col(|_| {});
+                            row.col(|_| {});
+                            row.col(|ui| {
+                                let label = format!(
+                                    "{} {} ({})",
+                                    if expanded { "⏷" } else { "⏵" },
+                                    prefix,
+                                    count
+                                );
+                                if ui
+                                    .add(
+                                        egui::Label::new(egui::RichText::new(label).strong())
+                                            .sense(egui::Sense::click()),
+                                    )
+                                    .clicked()
+                                {
+                                    if expanded {
+                                        self.expanded_groups.remove(*prefix);
+                                    } else {
+                                        self.expanded_groups.insert(prefix.to_string());
+                                    }
+                                }
+                            });
+                            return;
+                        }
+                        MainTableRow::Key(key) => *key,
+                    };
+                    row.col(|ui| {
+                        let position = self.selected_keys.iter().position(|k| k == key);
+                        let mut selected = position.is_some();
+                        if ui
+                            .checkbox(&mut selected, "")
+                            .on_hover_text("Include in Save selected as CSV")
+                            .changed()
+                        {
+                            match position {
+                                Some(i) => {
+                                    self.selected_keys.remove(i);
+                                }
+                                None => self.selected_keys.push(key.to_owned()),
+                            }
+                        }
+                    });
                     row.col(|ui| {
                         if ui.button("G").clicked() {
                             self.windows.push((
@@ -291,14 +2147,130 @@ impl App {
                         }
                     });
                     row.col(|ui| {
-                        ui.label(key);
+                        ui.add(egui::Label::new(key).sense(egui::Sense::click()))
+                            .context_menu(|ui| {
+                                let mut settings = self.settings.borrow_mut();
+                                let mut force_float = settings.force_float_keys.contains(key);
+                                if ui
+                                    .checkbox(&mut force_float, "Force float display")
+                                    .clicked()
+                                {
+                                    if force_float {
+                                        settings.force_float_keys.insert(key.to_owned());
+                                    } else {
+                                        settings.force_float_keys.remove(key);
+                                    }
+                                    ui.close_menu();
+                                }
+                                let mut unit = settings.units.get(key).cloned().unwrap_or_default();
+                                ui.horizontal(|ui| {
+                                    ui.label("Unit:");
+                                    if ui
+                                        .add(
+                                            egui::TextEdit::singleline(&mut unit)
+                                                .desired_width(60.0),
+                                        )
+                                        .changed()
+                                    {
+                                        if unit.is_empty() {
+                                            settings.units.remove(key);
+                                        } else {
+                                            settings.units.insert(key.to_owned(), unit);
+                                        }
+                                    }
+                                });
+                                let mut missing_value = settings.missing_values.get(key).copied();
+                                missing_value_ui(ui, &mut missing_value);
+                                match missing_value {
+                                    Some(v) => {
+                                        settings.missing_values.insert(key.to_owned(), v);
+                                    }
+                                    None => {
+                                        settings.missing_values.remove(key);
+                                    }
+                                }
+                                if missing_value.is_none() && settings.missing_value.is_some() {
+                                    let mut exempt = settings.missing_value_exempt.contains(key);
+                                    if ui
+                                        .checkbox(&mut exempt, "Ignore global missing value")
+                                        .changed()
+                                    {
+                                        if exempt {
+                                            settings.missing_value_exempt.insert(key.to_owned());
+                                        } else {
+                                            settings.missing_value_exempt.remove(key);
+                                        }
+                                    }
+                                }
+                            });
                     });
-                    row.col(|ui| {
-                        if let Some(v) = self.values.get_last_value_for_key(key) {
-                            ui.label(v.to_string());
+                    let last_value = self.values.get_last_value_for_key(key).map(|v| {
+                        let formatted = self.values.format_value(key, v);
+                        match self.values.unit(key) {
+                            Some(unit) => (v, format!("{} {}", formatted, unit)),
+                            None => (v, formatted),
                         }
                     });
+                    // 平均と標準偏差は整数チャンネルでも小数で表示する
+                    let stats = self.values.stats_for_key(key).map(|s| {
+                        [
+                            (s.min, self.values.format_value(key, s.min)),
+                            (s.max, self.values.format_value(key, s.max)),
+                            (
+                                s.mean,
+                                self.values.number_format().apply(&format!("{:.4}", s.mean)),
+                            ),
+                            (
+                                s.stddev,
+                                self.values
+                                    .number_format()
+                                    .apply(&format!("{:.4}", s.stddev)),
+                            ),
+                        ]
+                    });
+                    // 表示中の列をタブ区切りにした行
+                    let row_text = || {
+                        let mut cells = vec![
+                            key.to_owned(),
+                            last_value.as_ref().map(|v| v.1.clone()).unwrap_or_default(),
+                        ];
+                        for i in 0..stat_labels.len() {
+                            cells.push(stats.as_ref().map(|s| s[i].1.clone()).unwrap_or_default());
+                        }
+                        cells.join("\t")
+                    };
+                    let cells = std::iter::once(last_value.as_ref())
+                        .chain((0..stat_labels.len()).map(|i| stats.as_ref().map(|s| &s[i])));
+                    for cell in cells {
+                        row.col(|ui| {
+                            if let Some((raw, formatted)) = cell {
+                                let response =
+                                    ui.add(egui::Label::new(formatted).sense(egui::Sense::click()));
+                                super::cell_context_menu(&response, *raw, formatted, row_text);
+                            }
+                        });
+                    }
                 });
             });
     }
+
+    // 変化しないチャンネルを名前と値だけの一覧にまとめて表示する
+    fn constants(&self, ui: &mut egui::Ui, keys: &[&String]) {
+        egui::CollapsingHeader::new(format!("Constants ({})", keys.len()))
+            .id_salt("constants")
+            .show(ui, |ui| {
+                egui::Grid::new("constants_grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for key in keys {
+                            ui.label(key.as_str());
+                            if let Some(v) = self.values.get_last_value_for_key(key) {
+                                ui.label(self.values.format_value(key, v));
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+        ui.separator();
+    }
 }
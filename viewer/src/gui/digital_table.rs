@@ -1,15 +1,33 @@
-use crate::{range_check::range_check, values::Values};
+use crate::{
+    nits::NitsCommand, number_format::NumberFormat, range_check::range_check, values::Values,
+};
 use egui::{vec2, Color32, Context, Id, Layout, Ui};
 use egui_extras::{Column, TableBuilder};
-//use egui_file::FileDialog;
+use egui_file::FileDialog;
 use serde::{Deserialize, Serialize};
-use std::hash::Hash;
+use std::{
+    fs::File,
+    hash::Hash,
+    io::{BufWriter, Write},
+    path::Path,
+};
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
 enum DecodeType {
     Float32,
     Int24,
     RealNumber,
+    Int16,
+    Int24Signed,
+    Int32,
+    // 32bit 値の lsb ビット目から width ビットを取り出す
+    BitField { lsb: u8, width: u8 },
+    // 32bit 値の各バイトを ASCII 文字として表示する
+    Ascii,
+    // 32bit 値を NITS のコマンドとして分割し、コマンドタイプかペイロードを取り出す
+    // 分割位置は Settings::nits_payload_bits に従う
+    NitsCommandType,
+    NitsPayload,
 }
 
 impl std::fmt::Display for DecodeType {
@@ -18,11 +36,18 @@ impl std::fmt::Display for DecodeType {
             DecodeType::Float32 => write!(f, "32bit (float)"),
             DecodeType::Int24 => write!(f, "24bit (integer)"),
             DecodeType::RealNumber => write!(f, "Real Number"),
+            DecodeType::Int16 => write!(f, "16bit (integer)"),
+            DecodeType::Int24Signed => write!(f, "24bit (signed integer)"),
+            DecodeType::Int32 => write!(f, "32bit (integer)"),
+            DecodeType::BitField { .. } => write!(f, "Bit field"),
+            DecodeType::Ascii => write!(f, "ASCII"),
+            DecodeType::NitsCommandType => write!(f, "NITS command type"),
+            DecodeType::NitsPayload => write!(f, "NITS payload"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
 enum BinaryDisplayStyle {
     Hex,
     Dec,
@@ -41,30 +66,118 @@ impl std::fmt::Display for BinaryDisplayStyle {
     }
 }
 
+// 値のバイト順 (Big ならバイトを入れ替えてから表示する)
+#[derive(Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl std::fmt::Display for Endianness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endianness::Little => write!(f, "Little"),
+            Endianness::Big => write!(f, "Big"),
+        }
+    }
+}
+
+impl Endianness {
+    // 下位 bits ビットの中でバイト順を入れ替える
+    fn apply(self, raw: u32, bits: u32) -> u32 {
+        match self {
+            Endianness::Little => raw,
+            Endianness::Big => raw.swap_bytes() >> (32 - bits),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ColumnProperty {
     key: String,
     decode_type: DecodeType,
     display_style: BinaryDisplayStyle,
+    #[serde(default)]
+    endianness: Endianness,
     title: Option<String>,
     width: Option<u32>,
 }
 
 impl ColumnProperty {
-    fn added(&mut self) {
+    fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            decode_type: DecodeType::Float32,
+            display_style: BinaryDisplayStyle::Hex,
+            endianness: Endianness::Little,
+            title: None,
+            width: None,
+        }
+    }
+
+    fn decode(mut self, decode_type: DecodeType) -> Self {
+        self.decode_type = decode_type;
+        self
+    }
+
+    fn style(mut self, display_style: BinaryDisplayStyle) -> Self {
+        self.display_style = display_style;
+        self
+    }
+
+    fn endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    fn added(&mut self, nits_payload_bits: u32) {
         self.title = Some(self.get_title("\n"));
-        self.width = Some(self.get_width());
+        self.width = Some(self.get_width(nits_payload_bits));
     }
 
     fn get_title(&self, separator: &str) -> String {
-        match self.decode_type {
+        let title = match self.decode_type {
             DecodeType::Float32 => format!("{}{}32bit {}", self.key, separator, self.display_style),
             DecodeType::Int24 => format!("{}{}24bit {}", self.key, separator, self.display_style),
             DecodeType::RealNumber => format!("{}{}Real Number", self.key, separator),
+            DecodeType::Ascii => format!("{}{}ASCII", self.key, separator),
+            DecodeType::Int16 => format!("{}{}16bit {}", self.key, separator, self.display_style),
+            DecodeType::Int24Signed => {
+                format!(
+                    "{}{}24bit signed {}",
+                    self.key, separator, self.display_style
+                )
+            }
+            DecodeType::Int32 => {
+                format!("{}{}32bit int {}", self.key, separator, self.display_style)
+            }
+            DecodeType::BitField { lsb, width } => format!(
+                "{}{}bit {}..{} {}",
+                self.key,
+                separator,
+                lsb,
+                lsb as u32 + width as u32,
+                self.display_style
+            ),
+            DecodeType::NitsCommandType => {
+                format!("{}{}NITS type {}", self.key, separator, self.display_style)
+            }
+            DecodeType::NitsPayload => {
+                format!(
+                    "{}{}NITS payload {}",
+                    self.key, separator, self.display_style
+                )
+            }
+        };
+        if self.endianness == Endianness::Big && self.decode_type != DecodeType::RealNumber {
+            title + " BE"
+        } else {
+            title
         }
     }
 
-    fn get_width(&self) -> u32 {
+    fn get_width(&self, nits_payload_bits: u32) -> u32 {
         match self.decode_type {
             DecodeType::Float32 => match self.display_style {
                 BinaryDisplayStyle::Hex => 8,
@@ -79,13 +192,84 @@ impl ColumnProperty {
                 BinaryDisplayStyle::Bin => 24,
             },
             DecodeType::RealNumber => 10,
+            DecodeType::Ascii => 4,
+            DecodeType::Int16 => match self.display_style {
+                BinaryDisplayStyle::Hex => 4,
+                BinaryDisplayStyle::Dec => 5,
+                BinaryDisplayStyle::Oct => 6,
+                BinaryDisplayStyle::Bin => 16,
+            },
+            DecodeType::Int24Signed => match self.display_style {
+                BinaryDisplayStyle::Hex => 6,
+                BinaryDisplayStyle::Dec => 8,
+                BinaryDisplayStyle::Oct => 8,
+                BinaryDisplayStyle::Bin => 24,
+            },
+            DecodeType::Int32 => match self.display_style {
+                BinaryDisplayStyle::Hex => 8,
+                BinaryDisplayStyle::Dec => 10,
+                BinaryDisplayStyle::Oct => 11,
+                BinaryDisplayStyle::Bin => 32,
+            },
+            DecodeType::BitField { width, .. } => field_digits(self.display_style, width as u32),
+            DecodeType::NitsCommandType => field_digits(
+                self.display_style,
+                NitsCommand::new(0, nits_payload_bits).command_type_bits(),
+            ),
+            DecodeType::NitsPayload => field_digits(self.display_style, nits_payload_bits),
         }
     }
 
-    fn format(&self, value: f32) -> (String, Option<String>) {
+    // bits ビットの整数として表示する
+    // signed なら最上位ビットを符号として Dec で負の値を表示する (負の入力も受け付ける)
+    fn format_integer(
+        &self,
+        value: f64,
+        bits: u32,
+        signed: bool,
+        width: usize,
+    ) -> (String, Option<String>) {
+        let min = if signed {
+            -((1u64 << (bits - 1)) as f64)
+        } else {
+            0.0
+        };
+        let raw = (value.trunc() as i64 as u64 & ((1u64 << bits) - 1)) as u32;
+        let raw = self.endianness.apply(raw, bits);
+        (
+            match self.display_style {
+                BinaryDisplayStyle::Hex => format!("{:0width$x}", raw),
+                BinaryDisplayStyle::Dec if signed => {
+                    // 最上位ビットから符号拡張する
+                    let shift = 32 - bits;
+                    format!("{:width$}", ((raw << shift) as i32) >> shift)
+                }
+                BinaryDisplayStyle::Dec => format!("{:width$}", raw),
+                BinaryDisplayStyle::Oct => format!("{:0width$o}", raw),
+                BinaryDisplayStyle::Bin => format!("{:0width$b}", raw),
+            },
+            if !value.is_finite() {
+                Some(format!("Not finite ({})", value))
+            } else if value.fract() != 0.0 {
+                Some(format!("Not integer ({:.4})", value))
+            } else if range_check(&(min..((1u64 << bits) as f64)), value).is_err() {
+                Some(format!("Not within {}bit range ({:.4})", bits, value))
+            } else {
+                None
+            },
+        )
+    }
+
+    fn format(
+        &self,
+        value: f64,
+        number_format: &NumberFormat,
+        nits_payload_bits: u32,
+    ) -> (String, Option<String>) {
+        let digits = self.get_width(nits_payload_bits) as usize;
         match self.decode_type {
             DecodeType::Float32 => {
-                let bits = f32::to_bits(value);
+                let bits = self.endianness.apply(f32::to_bits(value as f32), 32);
                 (
                     match self.display_style {
                         BinaryDisplayStyle::Hex => format!("{:08x}", bits),
@@ -96,38 +280,75 @@ impl ColumnProperty {
                     None,
                 )
             }
-            DecodeType::Int24 => {
-                let bits = value.trunc() as u32;
+            DecodeType::Int24 => self.format_integer(value, 24, false, digits),
+            DecodeType::RealNumber => (number_format.format_float(value), None),
+            DecodeType::Ascii => {
+                // 上位バイトから順に並べ、表示できない文字は . にする
+                let bits = self.endianness.apply(f32::to_bits(value as f32), 32);
+                let text = bits
+                    .to_be_bytes()
+                    .iter()
+                    .map(|b| {
+                        if b.is_ascii_graphic() || *b == b' ' {
+                            *b as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                (text, None)
+            }
+            DecodeType::Int16 => self.format_integer(value, 16, false, digits),
+            DecodeType::Int24Signed => self.format_integer(value, 24, true, digits),
+            DecodeType::Int32 => self.format_integer(value, 32, false, digits),
+            DecodeType::BitField { lsb, width } => {
+                let (lsb, width) = (lsb as u32, width as u32);
+                let bits = self.endianness.apply(f32::to_bits(value as f32), 32);
+                let field = (bits as u64 >> lsb.min(31)) & ((1u64 << width.min(32)) - 1);
                 (
-                    match self.display_style {
-                        BinaryDisplayStyle::Hex => format!("{:06x}", bits),
-                        BinaryDisplayStyle::Dec => format!("{:8}", bits),
-                        BinaryDisplayStyle::Oct => format!("{:08o}", bits),
-                        BinaryDisplayStyle::Bin => format!("{:024b}", bits),
-                    },
-                    if value.fract() != 0.0 {
-                        Some(format!("Not integer ({:.4})", value))
-                    } else if let Err(_) = range_check(&(0.0..((1 << 24) as f32)), value) {
-                        Some(format!("Not within 24bit range ({:.4})", value))
-                    } else {
-                        None
-                    },
+                    format_field(self.display_style, field, digits),
+                    range_check(&(..=32), lsb + width)
+                        .err()
+                        .map(|e| format!("Bit field exceeds 32 bits: {}", e)),
                 )
             }
-            DecodeType::RealNumber => (value.to_string(), None),
+            DecodeType::NitsCommandType | DecodeType::NitsPayload => {
+                let bits = self.endianness.apply(f32::to_bits(value as f32), 32);
+                let command = NitsCommand::new(bits, nits_payload_bits);
+                let field = if self.decode_type == DecodeType::NitsCommandType {
+                    command.command_type().bits() as u32
+                } else {
+                    command.payload()
+                };
+                (format_field(self.display_style, field as u64, digits), None)
+            }
         }
     }
 }
 
+// width ビットの値を表示するのに必要な桁数
+fn field_digits(style: BinaryDisplayStyle, width: u32) -> u32 {
+    let width = width.min(32);
+    match style {
+        BinaryDisplayStyle::Hex => width.div_ceil(4),
+        BinaryDisplayStyle::Dec => ((1u64 << width) - 1).to_string().len() as u32,
+        BinaryDisplayStyle::Oct => width.div_ceil(3),
+        BinaryDisplayStyle::Bin => width,
+    }
+}
+
+fn format_field(style: BinaryDisplayStyle, field: u64, digits: usize) -> String {
+    match style {
+        BinaryDisplayStyle::Hex => format!("{:0digits$x}", field),
+        BinaryDisplayStyle::Dec => format!("{:digits$}", field),
+        BinaryDisplayStyle::Oct => format!("{:0digits$o}", field),
+        BinaryDisplayStyle::Bin => format!("{:0digits$b}", field),
+    }
+}
+
 impl Default for ColumnProperty {
     fn default() -> ColumnProperty {
-        ColumnProperty {
-            key: Default::default(),
-            decode_type: DecodeType::Float32,
-            display_style: BinaryDisplayStyle::Hex,
-            title: None,
-            width: None,
-        }
+        ColumnProperty::new(String::new())
     }
 }
 
@@ -136,8 +357,16 @@ pub struct DigitalTableWindow {
     id: Id,
     selector: ColumnProperty,
     columns: Vec<ColumnProperty>,
-    /*#[serde(skip, default)]
-    save_dialog: Option<FileDialog>,*/
+    // None なら全体の設定に従う
+    #[serde(default)]
+    auto_scroll: Option<bool>,
+    // 前の行と同じ表示の値を薄く表示する
+    #[serde(default)]
+    dim_unchanged: bool,
+    #[serde(skip, default = "super::default_following")]
+    following: bool,
+    #[serde(skip, default)]
+    save_dialog: Option<FileDialog>,
 }
 
 impl DigitalTableWindow {
@@ -146,21 +375,72 @@ impl DigitalTableWindow {
             id: Id::new(id),
             selector: Default::default(),
             columns: vec![],
-            //save_dialog: None,
+            auto_scroll: None,
+            dim_unchanged: false,
+            following: true,
+            save_dialog: None,
         }
     }
 
-    /*pub fn title(&self) -> String {
-        self.columns
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn title(&self) -> &str {
+        "Digital Table"
+    }
+
+    // 各列の表示形式のまま CSV に書き出す
+    fn save_csv(&self, path: &Path, values: &Values) -> Result<(), std::io::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header: Vec<_> = self
+            .columns
             .iter()
-            .map(|c| c.get_title(" "))
-            .collect::<Vec<_>>()
-            .join(",")
-    }*/
+            .map(|c| csv_field(&c.get_title(" ")))
+            .collect();
+        writer.write_fmt(format_args!("{}\n", header.join(",")))?;
+
+        let columns: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| (values.values_for_key(&column.key), column))
+            .collect();
+        let rows = values.sample_rows(self.columns.iter().map(|c| c.key.as_str()));
+        // CSV には表示桁数に関わらず全桁を書き出す
+        let number_format = NumberFormat {
+            decimal_places: None,
+            ..values.number_format()
+        };
+        let nits_payload_bits = values.nits_payload_bits();
+        for index in 0..rows.len() {
+            let row: Vec<_> = columns
+                .iter()
+                .enumerate()
+                .map(|(i, (v, column))| {
+                    v.as_ref()
+                        .and_then(|v| v.get(rows.sample_index(index, i)?))
+                        .map(|v| {
+                            csv_field(
+                                column
+                                    .format(*v, &number_format, nits_payload_bits)
+                                    .0
+                                    .trim(),
+                            )
+                        })
+                        .unwrap_or_default()
+                })
+                .collect();
+            writer.write_fmt(format_args!("{}\n", row.join(",")))?;
+        }
+        writer.flush()
+    }
 
     pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
-        egui::Window::new("Digital Table")
-            .id(self.id)
+        super::window(ctx, self.title(), self.id)
             .default_size(vec2(100.0, 200.0))
             .vscroll(true)
             .open(open)
@@ -171,7 +451,8 @@ impl DigitalTableWindow {
             egui::ComboBox::from_id_salt(self.id.with("key_selector"))
                 .selected_text(&self.selector.key)
                 .show_ui(ui, |ui| {
-                    for key in values.keys() {
+                    let query = super::key_filter_ui(ui, self.id.with("key_filter"));
+                    for key in super::filter_keys(values.keys(), &query) {
                         ui.selectable_value(&mut self.selector.key, key.to_owned(), key);
                     }
                 });
@@ -188,13 +469,51 @@ impl DigitalTableWindow {
                         DecodeType::Int24,
                         "24bit (integer)",
                     );
+                    ui.selectable_value(
+                        &mut self.selector.decode_type,
+                        DecodeType::Int16,
+                        "16bit (integer)",
+                    );
+                    ui.selectable_value(
+                        &mut self.selector.decode_type,
+                        DecodeType::Int24Signed,
+                        "24bit (signed integer)",
+                    );
+                    ui.selectable_value(
+                        &mut self.selector.decode_type,
+                        DecodeType::Int32,
+                        "32bit (integer)",
+                    );
+                    let is_bit_field =
+                        matches!(self.selector.decode_type, DecodeType::BitField { .. });
+                    if ui.selectable_label(is_bit_field, "Bit field").clicked() && !is_bit_field {
+                        self.selector.decode_type = DecodeType::BitField { lsb: 0, width: 8 };
+                    }
+                    ui.selectable_value(&mut self.selector.decode_type, DecodeType::Ascii, "ASCII");
+                    ui.selectable_value(
+                        &mut self.selector.decode_type,
+                        DecodeType::NitsCommandType,
+                        "NITS command type",
+                    );
+                    ui.selectable_value(
+                        &mut self.selector.decode_type,
+                        DecodeType::NitsPayload,
+                        "NITS payload",
+                    );
                     ui.selectable_value(
                         &mut self.selector.decode_type,
                         DecodeType::RealNumber,
                         "Real Number",
                     );
                 });
-            if self.selector.decode_type != DecodeType::RealNumber {
+            if let DecodeType::BitField { lsb, width } = &mut self.selector.decode_type {
+                ui.add(egui::DragValue::new(lsb).range(0..=31).prefix("LSB "));
+                ui.add(egui::DragValue::new(width).range(1..=32).prefix("Width "));
+            }
+            if !matches!(
+                self.selector.decode_type,
+                DecodeType::RealNumber | DecodeType::Ascii
+            ) {
                 egui::ComboBox::from_id_salt(self.id.with("display_style_selector"))
                     .selected_text(self.selector.display_style.to_string())
                     .show_ui(ui, |ui| {
@@ -220,37 +539,101 @@ impl DigitalTableWindow {
                         );
                     });
             }
+            if self.selector.decode_type != DecodeType::RealNumber {
+                egui::ComboBox::from_id_salt(self.id.with("endianness_selector"))
+                    .selected_text(self.selector.endianness.to_string())
+                    .show_ui(ui, |ui| {
+                        for endianness in [Endianness::Little, Endianness::Big] {
+                            ui.selectable_value(
+                                &mut self.selector.endianness,
+                                endianness,
+                                endianness.to_string(),
+                            );
+                        }
+                    });
+            }
             if ui.button("Add").clicked() && values.contains_key(&self.selector.key) {
-                let mut column = std::mem::take(&mut self.selector);
-                column.added();
+                // 続けて同じ形式の列を追加できるよう、キー以外の選択は残す
+                let mut column = ColumnProperty::new(std::mem::take(&mut self.selector.key))
+                    .decode(self.selector.decode_type)
+                    .style(self.selector.display_style)
+                    .endianness(self.selector.endianness);
+                column.added(values.nits_payload_bits());
                 self.columns.push(column);
             }
         });
 
-        /*#[cfg(not(target_arch = "wasm32"))]
+        #[cfg(not(target_arch = "wasm32"))]
         if ui.button("Save CSV").clicked() {
             let mut fd = FileDialog::save_file(None)
-                .default_filename(format!("{}.csv", self.title()))
+                .default_filename("digital_table.csv")
                 .title("Save as CSV");
             fd.open();
             self.save_dialog = Some(fd);
-        }*/
+        }
+        let jump_to_now = ui
+            .horizontal(|ui| {
+                super::auto_scroll_ui(ui, self.id, &mut self.auto_scroll);
+                ui.checkbox(&mut self.dim_unchanged, "Dim unchanged");
+                !self.following && ui.button("Jump to now").clicked()
+            })
+            .inner;
         ui.separator();
 
         let mut delete_column = None;
+        let mut swap_columns = None;
+        let mut widths = vec![None; self.columns.len()];
 
-        let table = TableBuilder::new(ui)
+        let table_values: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| (values.values_for_key(&column.key), column))
+            .collect();
+        let rows = values.sample_rows(self.columns.iter().map(|c| c.key.as_str()));
+        let max_len = rows.len();
+
+        let number_format = values.number_format();
+        let nits_payload_bits = values.nits_payload_bits();
+
+        // 列幅は文字数で保存し、数字1文字分の幅を掛けて使う
+        let char_width =
+            ui.fonts(|f| f.glyph_width(&egui::TextStyle::Body.resolve(ui.style()), '0'));
+        // 列を並べ替えたらリサイズの状態を作り直して保存した幅を使う
+        let order: Vec<_> = self.columns.iter().map(|c| c.get_title(" ")).collect();
+        let mut table = TableBuilder::new(ui)
+            .id_salt(self.id.with(order))
             .cell_layout(Layout::left_to_right(egui::Align::Center))
-            .columns(Column::auto(), self.columns.len())
-            .stick_to_bottom(true);
+            .stick_to_bottom(super::auto_scroll(self.auto_scroll, values));
+        for column in &self.columns {
+            let width = column
+                .width
+                .unwrap_or_else(|| column.get_width(nits_payload_bits));
+            table = table.column(
+                Column::initial(width as f32 * char_width)
+                    .at_least(char_width)
+                    .resizable(true)
+                    .clip(true),
+            );
+        }
+        if jump_to_now {
+            table = table.scroll_to_row(max_len.saturating_sub(1), Some(egui::Align::BOTTOM));
+        }
 
-        table
+        let output = table
             .header(20.0, |mut header| {
+                let len = self.columns.len();
                 for (i, column) in self.columns.iter().enumerate() {
                     header.col(|ui| {
+                        widths[i] = Some((ui.max_rect().width() / char_width).round() as u32);
                         if let Some(title) = &column.title {
                             ui.strong(title);
                         }
+                        if i > 0 && ui.small_button("◀").clicked() {
+                            swap_columns = Some((i - 1, i));
+                        }
+                        if i + 1 < len && ui.small_button("▶").clicked() {
+                            swap_columns = Some((i, i + 1));
+                        }
                         if ui.button("X").clicked() {
                             delete_column = Some(i);
                         }
@@ -258,55 +641,244 @@ impl DigitalTableWindow {
                 }
             })
             .body(|body| {
-                let mut table_values: Vec<_> = self
-                    .columns
-                    .iter()
-                    .map(|column| (values.values_for_key(&column.key), column))
-                    .collect();
-                let max_len = table_values
-                    .iter()
-                    .map(|v| v.0.as_ref().map(|v| v.len()).unwrap_or_default())
-                    .max()
-                    .unwrap_or_default();
                 body.rows(20.0, max_len, |mut row| {
                     let index = row.index();
-                    for (iter, column) in table_values.iter_mut() {
+                    // i 列目の index 行目の値
+                    let sample_at = |i: usize, index: usize| {
+                        table_values[i]
+                            .0
+                            .as_ref()
+                            .and_then(|v| v.get(rows.sample_index(index, i)?))
+                            .copied()
+                    };
+                    let sample = |i: usize| sample_at(i, index);
+                    let row_text = || {
+                        table_values
+                            .iter()
+                            .enumerate()
+                            .map(|(i, (_, column))| {
+                                sample(i)
+                                    .map(|v| {
+                                        column
+                                            .format(v, &number_format, nits_payload_bits)
+                                            .0
+                                            .trim()
+                                            .to_owned()
+                                    })
+                                    .unwrap_or_default()
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\t")
+                    };
+                    for (i, (_, column)) in table_values.iter().enumerate() {
                         row.col(|ui| {
-                            if let Some(it) = iter.as_mut() {
-                                let offset = max_len - it.len();
-                                if offset <= index {
-                                    if let Some(v) = it.get(index - offset) {
-                                        let (label_text, tooltip) = column.format(*v);
-                                        if let Some(tooltip_text) = tooltip {
-                                            ui.colored_label(
-                                                Color32::from_rgb(255, 0, 0),
-                                                label_text,
-                                            )
-                                            .on_hover_text(tooltip_text);
-                                        } else {
-                                            ui.label(label_text);
-                                        }
-                                    } else {
-                                        *iter = None;
-                                    }
+                            if let Some(v) = sample(i) {
+                                let (label_text, tooltip) =
+                                    column.format(v, &number_format, nits_payload_bits);
+                                let unchanged = self.dim_unchanged
+                                    && index
+                                        .checked_sub(1)
+                                        .and_then(|prev| sample_at(i, prev))
+                                        .is_some_and(|p| {
+                                            column.format(p, &number_format, nits_payload_bits).0
+                                                == label_text
+                                        });
+                                let label = egui::Label::new(if tooltip.is_some() {
+                                    egui::RichText::new(&label_text)
+                                        .color(Color32::from_rgb(255, 0, 0))
+                                } else if unchanged {
+                                    egui::RichText::new(&label_text).weak()
+                                } else {
+                                    egui::RichText::new(&label_text)
+                                })
+                                .sense(egui::Sense::click());
+                                let mut response = ui.add(label);
+                                if let Some(tooltip_text) = tooltip {
+                                    response = response.on_hover_text(tooltip_text);
                                 }
+                                super::cell_context_menu(&response, v, label_text.trim(), row_text);
                             }
                         });
                     }
                 });
             });
 
+        self.following = super::is_scrolled_to_bottom(&output);
+
+        for (column, width) in self.columns.iter_mut().zip(widths) {
+            if width.is_some() {
+                column.width = width;
+            }
+        }
+        if let Some((a, b)) = swap_columns {
+            self.columns.swap(a, b);
+        }
         if let Some(i) = delete_column {
             self.columns.remove(i);
         }
 
-        /*if let Some(save_dialog) = self.save_dialog.as_mut() {
+        if let Some(save_dialog) = self.save_dialog.as_mut() {
             if save_dialog.show(ui.ctx()).selected() {
-                if let Some(path) = save_dialog.path() {
-                    let _ = values.save_csv(path, self.keys.iter());
+                if let Some(path) = save_dialog.path().map(Path::to_path_buf) {
+                    if let Err(e) = self.save_csv(&path, values) {
+                        log::error!("failed to save digital table: {}", e);
+                    }
                 }
                 self.save_dialog = None;
             }
-        }*/
+        }
+    }
+}
+
+// カンマなどを含む値を CSV のフィールドとして引用符で囲む
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nits::DEFAULT_PAYLOAD_BITS;
+
+    const STYLES: [BinaryDisplayStyle; 4] = [
+        BinaryDisplayStyle::Hex,
+        BinaryDisplayStyle::Dec,
+        BinaryDisplayStyle::Oct,
+        BinaryDisplayStyle::Bin,
+    ];
+
+    fn format(
+        decode: DecodeType,
+        style: BinaryDisplayStyle,
+        value: f64,
+    ) -> (String, Option<String>) {
+        ColumnProperty::new("key")
+            .decode(decode)
+            .style(style)
+            .format(value, &NumberFormat::default(), DEFAULT_PAYLOAD_BITS)
+    }
+
+    #[test]
+    fn formats_every_decode_and_style() {
+        // (表示形式, 値, Hex・Dec・Oct・Bin の表示)
+        let cases = [
+            (
+                DecodeType::Float32,
+                1.0,
+                [
+                    "3f800000",
+                    "1065353216",
+                    "07740000000",
+                    "00111111100000000000000000000000",
+                ],
+            ),
+            (
+                DecodeType::Int24,
+                0x12_3456 as f64,
+                ["123456", " 1193046", "04432126", "000100100011010001010110"],
+            ),
+            (DecodeType::RealNumber, 1.5, ["1.5"; 4]),
+            (
+                DecodeType::Int16,
+                0x3456 as f64,
+                ["3456", "13398", "032126", "0011010001010110"],
+            ),
+            (
+                DecodeType::Int24Signed,
+                -2.0,
+                ["fffffe", "      -2", "77777776", "111111111111111111111110"],
+            ),
+            (
+                DecodeType::Int32,
+                0x1234_5678 as f64,
+                [
+                    "12345678",
+                    " 305419896",
+                    "02215053170",
+                    "00010010001101000101011001111000",
+                ],
+            ),
+            (
+                DecodeType::BitField { lsb: 20, width: 8 },
+                1.0,
+                ["f8", "248", "370", "11111000"],
+            ),
+            (
+                DecodeType::Ascii,
+                f32::from_bits(0x4142_4344) as f64,
+                ["ABCD"; 4],
+            ),
+            (
+                DecodeType::NitsCommandType,
+                f32::from_bits(0x0ab1_2345) as f64,
+                ["0a", " 10", "012", "00001010"],
+            ),
+            (
+                DecodeType::NitsPayload,
+                f32::from_bits(0x0ab1_2345) as f64,
+                ["b12345", "11608901", "54221505", "101100010010001101000101"],
+            ),
+        ];
+        for (decode, value, expected) in cases {
+            for (style, expected) in STYLES.into_iter().zip(expected) {
+                assert_eq!(
+                    format(decode, style, value),
+                    (expected.to_owned(), None),
+                    "{} {}",
+                    decode,
+                    style
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn int24_tooltips_report_unrepresentable_values() {
+        let tooltip = |value: f64| format(DecodeType::Int24, BinaryDisplayStyle::Hex, value).1;
+        let starts_with =
+            |value: f64, prefix: &str| tooltip(value).is_some_and(|t| t.starts_with(prefix));
+        assert!(starts_with(-1.0, "Not within 24bit range"));
+        assert_eq!(tooltip(16777215.0), None);
+        assert!(starts_with(16777216.0, "Not within 24bit range"));
+        assert!(starts_with(3.5, "Not integer"));
+        assert!(starts_with(f64::NAN, "Not finite"));
+        assert!(starts_with(f64::INFINITY, "Not finite"));
+    }
+
+    #[test]
+    fn nits_columns_follow_the_payload_split() {
+        let value = f32::from_bits(0x0ab1_2345) as f64;
+        let format = |decode, payload_bits| {
+            ColumnProperty::new("NITS N16")
+                .decode(decode)
+                .format(value, &NumberFormat::default(), payload_bits)
+                .0
+        };
+        assert_eq!(format(DecodeType::NitsCommandType, 24), "0a");
+        assert_eq!(format(DecodeType::NitsPayload, 24), "b12345");
+        // コマンドタイプ 4 ビット、ペイロード 28 ビットの分割
+        assert_eq!(format(DecodeType::NitsCommandType, 28), "0");
+        assert_eq!(format(DecodeType::NitsPayload, 28), "ab12345");
+    }
+
+    #[test]
+    fn big_endian_swaps_bytes_within_the_width() {
+        let column = ColumnProperty::new("key")
+            .decode(DecodeType::Int24)
+            .endianness(Endianness::Big);
+        assert_eq!(
+            column
+                .format(
+                    0x12_3456 as f64,
+                    &NumberFormat::default(),
+                    DEFAULT_PAYLOAD_BITS
+                )
+                .0,
+            "563412"
+        );
     }
 }
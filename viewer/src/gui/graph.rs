@@ -1,8 +1,19 @@
-use crate::values::Values;
-use egui::{vec2, Context, Id, ScrollArea, Ui};
-use egui_plot::{Legend, Line, Plot, PlotPoints};
-use serde::{Deserialize, Serialize};
-use std::hash::Hash;
+use super::{image_export, measurements::Measurement};
+use crate::{settings::LineColors, values::Values};
+use egui::{vec2, Color32, ColorImage, Context, Id, Rect, ScrollArea, Ui, Vec2b};
+#[cfg(not(target_arch = "wasm32"))]
+use egui_file::FileDialog;
+use egui_plot::{AxisHints, HLine, Legend, Line, Plot, PlotMemory, PlotPoints, Points, VLine};
+use serde::{
+    de::{MapAccess, SeqAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    fmt,
+    hash::Hash,
+    ops::{Range, RangeInclusive},
+};
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 enum Corner {
@@ -53,15 +64,568 @@ impl From<HPlacement> for egui_plot::HPlacement {
     }
 }
 
+impl HPlacement {
+    fn opposite(self) -> Self {
+        match self {
+            HPlacement::Left => HPlacement::Right,
+            HPlacement::Right => HPlacement::Left,
+        }
+    }
+}
+
+// 第2軸の線を主軸の座標に重ねる一次変換 (どちらも YScale を掛けた後の座標)
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SecondaryAxis {
+    scale: f64,
+    offset: f64,
+}
+
+impl SecondaryAxis {
+    // 第2軸の線の範囲を主軸の線の範囲に合わせる
+    fn fit(primary: (f64, f64), secondary: (f64, f64)) -> Self {
+        let primary_span = primary.1 - primary.0;
+        let secondary_span = secondary.1 - secondary.0;
+        let scale = if primary_span > 0.0 && secondary_span > 0.0 {
+            primary_span / secondary_span
+        } else {
+            1.0
+        };
+        Self {
+            scale,
+            offset: primary.0 - secondary.0 * scale,
+        }
+    }
+
+    fn apply_points(self, points: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+        points
+            .into_iter()
+            .map(|[x, y]| [x, y * self.scale + self.offset])
+            .collect()
+    }
+
+    // 主軸の座標を第2軸の座標に戻す
+    fn invert(self, y: f64) -> f64 {
+        (y - self.offset) / self.scale
+    }
+
+    // 主軸の目盛りの位置に第2軸の値を表示する
+    fn hints(self, y_scale: YScale, placement: HPlacement, label: String) -> AxisHints<'static> {
+        AxisHints::new_y()
+            .placement(egui_plot::HPlacement::from(placement))
+            .label(label)
+            .formatter(move |mark, _| {
+                let y = y_scale.invert(self.invert(mark.value));
+                match y_scale {
+                    YScale::Linear => {
+                        let decimals = (-(mark.step_size / self.scale).log10().round()).max(0.0);
+                        let decimals = decimals as usize;
+                        egui::emath::format_with_decimals_in_range(y, decimals..=decimals)
+                    }
+                    YScale::Log10 => format!("{:.3e}", y),
+                }
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum YScale {
+    #[default]
+    Linear,
+    Log10,
+}
+
+impl YScale {
+    // 描画する y 座標に変換する (対数で表せない値は None)
+    fn apply(self, y: f64) -> Option<f64> {
+        match self {
+            YScale::Linear => Some(y),
+            YScale::Log10 => (y > 0.0).then(|| y.log10()),
+        }
+    }
+
+    fn apply_points(self, points: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+        match self {
+            YScale::Linear => points,
+            YScale::Log10 => points
+                .into_iter()
+                .filter_map(|[x, y]| Some([x, self.apply(y)?]))
+                .collect(),
+        }
+    }
+
+    // apply の逆変換
+    fn invert(self, y: f64) -> f64 {
+        match self {
+            YScale::Linear => y,
+            YScale::Log10 => 10f64.powf(y),
+        }
+    }
+
+    // 対数軸の目盛りを元の値で表示する
+    fn apply_to_plot(self, plot: Plot<'_>) -> Plot<'_> {
+        match self {
+            YScale::Linear => plot,
+            YScale::Log10 => plot
+                .y_axis_label("log10")
+                .y_axis_formatter(|mark, _| format!("{:.3e}", 10f64.powf(mark.value))),
+        }
+    }
+}
+
+// 軸の表示範囲 (Manual ならデータが増えても動かさない)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+enum AxisRange {
+    #[default]
+    Auto,
+    Manual {
+        min: f64,
+        max: f64,
+    },
+}
+
+impl AxisRange {
+    fn manual(self) -> Option<(f64, f64)> {
+        match self {
+            AxisRange::Manual { min, max } if min < max => Some((min, max)),
+            _ => None,
+        }
+    }
+
+    // Manual に切り替えたときは今表示している範囲 (current) から始める
+    fn ui(&mut self, ui: &mut Ui, current: (f64, f64)) {
+        let mut manual = matches!(self, AxisRange::Manual { .. });
+        let mut changed = ui.radio_value(&mut manual, false, "Auto").changed();
+        changed |= ui.radio_value(&mut manual, true, "Manual").changed();
+        if changed {
+            *self = if manual {
+                AxisRange::Manual {
+                    min: current.0,
+                    max: current.1,
+                }
+            } else {
+                AxisRange::Auto
+            };
+        }
+        if let AxisRange::Manual { min, max } = self {
+            let speed = ((*max - *min).abs() / 100.0).max(1e-6);
+            ui.horizontal(|ui| {
+                ui.label("Min");
+                ui.add(egui::DragValue::new(min).speed(speed));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Max");
+                ui.add(egui::DragValue::new(max).speed(speed));
+            });
+        }
+    }
+}
+
+// locked の軸は include_x / include_y の範囲をそのまま使い、ドラッグやズームでも動かさない
+fn lock_plot_axes(
+    mut plot: Plot<'_>,
+    x: Option<(f64, f64)>,
+    y: Option<(f64, f64)>,
+) -> (Plot<'_>, Vec2b) {
+    let locked = Vec2b::new(x.is_some(), y.is_some());
+    if let Some((min, max)) = x {
+        plot = plot.include_x(min).include_x(max);
+    }
+    if let Some((min, max)) = y {
+        plot = plot.include_y(min).include_y(max);
+    }
+    if locked.any() {
+        plot = plot
+            .auto_bounds(!locked)
+            .allow_drag(!locked)
+            .allow_zoom(!locked);
+    }
+    (plot, locked)
+}
+
+// ボックスズームなどで外れた固定軸の自動範囲を戻す
+fn keep_axes_locked(ui: &mut egui_plot::PlotUi, locked: Vec2b) {
+    let auto_bounds = ui.auto_bounds();
+    if (locked.x && !auto_bounds.x) || (locked.y && !auto_bounds.y) {
+        ui.set_auto_bounds(Vec2b::new(
+            auto_bounds.x || locked.x,
+            auto_bounds.y || locked.y,
+        ));
+    }
+}
+
+// 系列に掛ける変換 (時間は点列の x の秒を使う)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum Transform {
+    #[default]
+    None,
+    Derivative,
+    Integral,
+}
+
+impl Transform {
+    fn apply(self, points: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+        match self {
+            Transform::None => points,
+            // 隣り合う点の差を時間の差で割る
+            Transform::Derivative => points
+                .windows(2)
+                .filter(|w| w[1][0] > w[0][0])
+                .map(|w| [w[1][0], (w[1][1] - w[0][1]) / (w[1][0] - w[0][0])])
+                .collect(),
+            // 最初の点を 0 とした台形積分
+            Transform::Integral => {
+                let mut sum = 0.0;
+                let mut result = Vec::with_capacity(points.len());
+                for (i, [x, y]) in points.iter().enumerate() {
+                    if let Some([px, py]) = i.checked_sub(1).map(|j| points[j]) {
+                        sum += (y + py) / 2.0 * (x - px);
+                    }
+                    result.push([*x, sum]);
+                }
+                result
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LineConfig {
+    visible: bool,
+    // 移動中央値を重ねて描画するときの窓の長さ (1 以下なら描画しない)
+    #[serde(default)]
+    median_window: usize,
+    // 凡例に表示する名前と線の色 (None ならキーと自動の色)
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(default)]
+    color: Option<Color32>,
+    #[serde(default)]
+    transform: Transform,
+    // 主軸と反対側の第2軸に描画する
+    #[serde(default)]
+    secondary_axis: bool,
+}
+
+impl Default for LineConfig {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            median_window: 0,
+            alias: None,
+            color: None,
+            transform: Transform::None,
+            secondary_axis: false,
+        }
+    }
+}
+
+impl LineConfig {
+    fn display_name(&self, key: &str) -> String {
+        let name = self.alias.as_deref().unwrap_or(key);
+        match self.transform {
+            Transform::None => name.to_owned(),
+            Transform::Derivative => format!("d/dt {}", name),
+            Transform::Integral => format!("∫{} dt", name),
+        }
+    }
+
+    fn line(&self, key: &str, points: Vec<[f64; 2]>, color: Color32) -> Line {
+        Line::new(PlotPoints::from(points))
+            .name(self.display_name(key))
+            .color(color)
+    }
+}
+
+// 追加した順に並べた線ごとの設定
+#[derive(Clone, Default)]
+struct LineConfigs(Vec<(String, LineConfig)>);
+
+impl LineConfigs {
+    fn new(key: String) -> Self {
+        Self(vec![(key, LineConfig::default())])
+    }
+
+    fn get(&self, key: &str) -> Option<&LineConfig> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, c)| c)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut LineConfig> {
+        self.0.iter_mut().find(|(k, _)| k == key).map(|(_, c)| c)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // なければ末尾に default を追加する
+    fn get_or_insert(&mut self, key: &str, default: LineConfig) -> &mut LineConfig {
+        let index = match self.0.iter().position(|(k, _)| k == key) {
+            Some(index) => index,
+            None => {
+                self.0.push((key.to_owned(), default));
+                self.0.len() - 1
+            }
+        };
+        &mut self.0[index].1
+    }
+
+    fn remove(&mut self, key: &str) -> Option<LineConfig> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(index).1)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &LineConfig)> {
+        self.0.iter().map(|(k, c)| (k, c))
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut LineConfig)> {
+        self.0.iter_mut().map(|(k, c)| (&*k, c))
+    }
+}
+
+impl Serialize for LineConfigs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, config) in &self.0 {
+            map.serialize_entry(key, config)?;
+        }
+        map.end()
+    }
+}
+
+// 以前の保存データはキーの列 (keys: Vec<String>) なので、その形式も読めるようにする
+impl<'de> Deserialize<'de> for LineConfigs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LineConfigsVisitor;
+
+        impl<'de> Visitor<'de> for LineConfigsVisitor {
+            type Value = LineConfigs;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of line configs or a list of keys")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut lines = LineConfigs::default();
+                while let Some((key, config)) = map.next_entry::<String, LineConfig>()? {
+                    *lines.get_or_insert(&key, LineConfig::default()) = config;
+                }
+                Ok(lines)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut lines = LineConfigs::default();
+                while let Some(key) = seq.next_element::<String>()? {
+                    lines.get_or_insert(&key, LineConfig::default());
+                }
+                Ok(lines)
+            }
+        }
+
+        deserializer.deserialize_any(LineConfigsVisitor)
+    }
+}
+
+// 直近 window 点の中央値を並べた点列
+// 窓内の値を整列した状態で保ち、1点ずつ入れ替える
+fn rolling_median(points: &[[f64; 2]], window: usize) -> Vec<[f64; 2]> {
+    let mut sorted: Vec<f64> = Vec::with_capacity(window);
+    let mut result = Vec::with_capacity(points.len());
+    for (i, [x, y]) in points.iter().enumerate() {
+        if i >= window {
+            let old = points[i - window][1];
+            let index = sorted.partition_point(|v| v.total_cmp(&old).is_lt());
+            sorted.remove(index);
+        }
+        let index = sorted.partition_point(|v| v.total_cmp(y).is_lt());
+        sorted.insert(index, *y);
+        let len = sorted.len();
+        let median = if len.is_multiple_of(2) {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
+        } else {
+            sorted[len / 2]
+        };
+        result.push([*x, median]);
+    }
+    result
+}
+
+// 目安として引く水平線 (vertical なら垂直線)
+#[derive(Serialize, Deserialize, Clone)]
+struct RefLine {
+    value: f64,
+    label: String,
+    color: Color32,
+    vertical: bool,
+}
+
+impl RefLine {
+    fn new(vertical: bool) -> Self {
+        Self {
+            value: 0.0,
+            label: String::new(),
+            color: Color32::RED,
+            vertical,
+        }
+    }
+}
+
+// x の位置の値を前後のサンプルから線形補間する (点列の範囲外なら None)
+fn interpolate(points: &[[f64; 2]], x: f64) -> Option<f64> {
+    let i = points.partition_point(|[px, _]| *px < x);
+    let [x1, y1] = *points.get(i)?;
+    if x1 == x {
+        return Some(y1);
+    }
+    let [x0, y0] = *points.get(i.checked_sub(1)?)?;
+    Some(y0 + (y1 - y0) * (x - x0) / (x1 - x0))
+}
+
+// 描画する点を減らす (元のデータはそのまま)
+// x_range があればその範囲 (と前後1点) に絞り、x 方向を buckets 個の区間に分けて
+// 各区間の y が最小と最大の点だけを残す
+fn downsample(
+    points: Vec<[f64; 2]>,
+    x_range: Option<RangeInclusive<f64>>,
+    buckets: usize,
+) -> Vec<[f64; 2]> {
+    let points = match x_range {
+        Some(range) => {
+            let start = points
+                .partition_point(|[x, _]| x < range.start())
+                .saturating_sub(1);
+            let end = (points.partition_point(|[x, _]| x <= range.end()) + 1).min(points.len());
+            points[start..end.max(start)].to_vec()
+        }
+        None => points,
+    };
+    if buckets == 0 || points.len() <= buckets * 2 {
+        return points;
+    }
+    let x0 = points[0][0];
+    let width = (points[points.len() - 1][0] - x0) / buckets as f64;
+    if width <= 0.0 {
+        return points;
+    }
+    let mut result = Vec::with_capacity(buckets * 2);
+    let mut flush = |min: [f64; 2], max: [f64; 2]| {
+        if min == max {
+            result.push(min);
+        } else if min[0] <= max[0] {
+            result.extend([min, max]);
+        } else {
+            result.extend([max, min]);
+        }
+    };
+    let mut current: Option<(usize, [f64; 2], [f64; 2])> = None;
+    for p in points {
+        let bucket = (((p[0] - x0) / width) as usize).min(buckets - 1);
+        match &mut current {
+            Some((b, min, max)) if *b == bucket => {
+                if p[1] < min[1] {
+                    *min = p;
+                }
+                if p[1] > max[1] {
+                    *max = p;
+                }
+            }
+            _ => {
+                if let Some((_, min, max)) = current {
+                    flush(min, max);
+                }
+                current = Some((bucket, p, p));
+            }
+        }
+    }
+    if let Some((_, min, max)) = current {
+        flush(min, max);
+    }
+    result
+}
+
+fn default_downsampling() -> bool {
+    true
+}
+
+// 前後 window / 2 点ずつの単純移動平均を並べた点列
+// 端では取れる範囲だけで平均する
+fn moving_average(points: &[[f64; 2]], window: usize) -> Vec<[f64; 2]> {
+    let half = window / 2;
+    let mut sums = Vec::with_capacity(points.len() + 1);
+    sums.push(0.0);
+    for [_, y] in points {
+        sums.push(sums.last().unwrap() + y);
+    }
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, [x, _])| {
+            let start = i.saturating_sub(half);
+            let end = (i + half + 1).min(points.len());
+            [*x, (sums[end] - sums[start]) / (end - start) as f64]
+        })
+        .collect()
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LineGraph {
     id: Id,
     title: String,
-    keys: Vec<String>,
+    #[serde(alias = "keys")]
+    lines: LineConfigs,
+    // グラフから外したキーの設定 (追加し直すと元の設定に戻る)
+    #[serde(default)]
+    removed_lines: LineConfigs,
     legend_position: Corner,
     x_axis_position: VPlacement,
     y_axis_position: HPlacement,
     period: usize,
+    // x 軸を常に期間分の幅で固定し、現在を右端に置く
+    #[serde(default)]
+    fixed_x_window: bool,
+    // 外れ値を除いた範囲 (2〜98パーセンタイル) で y 軸を決める
+    #[serde(default)]
+    robust_y_bounds: bool,
+    // 移動平均の窓の長さ (0 なら平滑化しない)
+    #[serde(default)]
+    smoothing: usize,
+    #[serde(default)]
+    y_scale: YScale,
+    #[serde(default)]
+    ref_lines: Vec<RefLine>,
+    #[serde(default)]
+    y_range: AxisRange,
+    // カーソル位置の各系列の値をツールチップに出す
+    #[serde(default)]
+    show_readout: bool,
+    // 描画する点をグラフの幅に合わせて間引く
+    #[serde(default = "default_downsampling")]
+    downsampling: bool,
+    #[serde(skip, default)]
+    show_line_settings: bool,
+    #[serde(skip, default = "super::default_following")]
+    following: bool,
+    // 最後にポインタがあった x 座標
+    #[serde(skip, default)]
+    cursor: Option<f64>,
+    #[serde(skip, default)]
+    capture_request: Option<Measurement>,
+    // 全ウィンドウ共通の印を付けた時刻 (Values の時刻)
+    #[serde(skip, default)]
+    marked_time: Option<f64>,
+    // 印の変更の要求 (Some(None) は印を消す)
+    #[serde(skip, default)]
+    mark_request: Option<Option<f64>>,
+    // App のダイアログに出すファイルのエラー
+    #[serde(skip, default)]
+    file_error: Option<String>,
+    // 保存待ちの PNG
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip, default)]
+    image_data: Option<Vec<u8>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip, default)]
+    image_dialog: Option<FileDialog>,
 }
 
 impl LineGraph {
@@ -70,17 +634,332 @@ impl LineGraph {
         Self {
             id,
             title: key.clone(),
-            keys: vec![key],
+            lines: LineConfigs::new(key),
+            removed_lines: LineConfigs::default(),
             legend_position: Corner::LeftTop,
             x_axis_position: VPlacement::Bottom,
             y_axis_position: HPlacement::Right,
             period: 3600,
+            fixed_x_window: false,
+            robust_y_bounds: false,
+            smoothing: 0,
+            y_scale: YScale::Linear,
+            ref_lines: vec![],
+            y_range: AxisRange::Auto,
+            show_readout: false,
+            downsampling: true,
+            show_line_settings: false,
+            following: true,
+            cursor: None,
+            capture_request: None,
+            marked_time: None,
+            mark_request: None,
+            file_error: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            image_data: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            image_dialog: None,
         }
     }
 
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn add_key(&mut self, key: String) {
+        let config = self.removed_lines.remove(&key).unwrap_or_default();
+        self.lines.get_or_insert(&key, config).visible = true;
+        self.update_title();
+    }
+
+    // 設定は removed_lines に残し、次に追加したときに使う
+    fn remove_key(&mut self, key: &str) -> bool {
+        let Some(config) = self.lines.remove(key) else {
+            return false;
+        };
+        self.removed_lines.remove(key);
+        self.removed_lines.get_or_insert(key, config);
+        self.update_title();
+        true
+    }
+
+    fn update_title(&mut self) {
+        self.title = self.visible_keys().cloned().collect::<Vec<_>>().join(", ");
+    }
+
+    // 設定を残したまま表示/非表示だけを切り替える
+    fn toggle_visible(&mut self, key: &str) {
+        let config = self.lines.get_or_insert(
+            key,
+            LineConfig {
+                visible: false,
+                ..Default::default()
+            },
+        );
+        config.visible = !config.visible;
+        self.update_title();
+    }
+
+    fn is_visible(&self, key: &str) -> bool {
+        self.lines.get(key).is_some_and(|c| c.visible)
+    }
+
+    fn visible_keys(&self) -> impl Iterator<Item = &String> {
+        self.lines.iter().filter(|(_, c)| c.visible).map(|(k, _)| k)
+    }
+
+    // グラフに描画する点列 (x は最新のサンプルを 0 とした秒)
+    // 値のコピーやカーソル位置の値も描画した線と揃うように smoothing を掛ける
+    fn line_points(&self, values: &Values, key: &str) -> Option<Vec<[f64; 2]>> {
+        Some(self.smooth(self.raw_line_points(values, key)?))
+    }
+
+    fn smooth(&self, points: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+        if self.smoothing > 1 {
+            moving_average(&points, self.smoothing)
+        } else {
+            points
+        }
+    }
+
+    // smoothing を掛ける前の点列 (移動中央値は元の値から求める)
+    // NaN や無限大は線が途切れたり消えたりするので飛ばす
+    fn raw_line_points(&self, values: &Values, key: &str) -> Option<Vec<[f64; 2]>> {
+        let len = values.values_for_key(key)?.len();
+        let now = values.latest_time().unwrap_or(0.0);
+        let iter = values.iter_with_time_for_key(key)?;
+        let points = iter
+            .skip(len.saturating_sub(self.period))
+            .filter(|(_, v)| v.is_finite())
+            .map(|(t, v)| [t - now, v])
+            .collect();
+        let transform = self.lines.get(key).map(|c| c.transform).unwrap_or_default();
+        Some(transform.apply(points))
+    }
+
+    // line_points で飛ばしたサンプルの数
+    fn non_finite_count(&self, values: &Values, key: &str) -> usize {
+        values.values_for_key(key).map_or(0, |v| {
+            v.iter()
+                .skip(v.len().saturating_sub(self.period))
+                .filter(|v| !v.is_finite())
+                .count()
+        })
+    }
+
+    // 第2軸を使うときに第2軸に描画する系列か
+    fn on_secondary_axis(&self, key: &str, secondary_axis: Option<SecondaryAxis>) -> bool {
+        secondary_axis.is_some() && self.lines.get(key).is_some_and(|c| c.secondary_axis)
+    }
+
+    // 主軸または第2軸に描画する表示中の系列の、描画する y 座標の範囲
+    fn y_extent(&self, values: &Values, secondary: bool) -> Option<(f64, f64)> {
+        self.lines
+            .iter()
+            .filter(|(_, c)| c.visible && c.secondary_axis == secondary)
+            .filter_map(|(k, _)| self.line_points(values, k))
+            .flatten()
+            .filter_map(|[_, y]| self.y_scale.apply(y))
+            .fold(None, |range, y| match range {
+                None => Some((y, y)),
+                Some((min, max)) => Some((f64::min(min, y), f64::max(max, y))),
+            })
+    }
+
+    // 主軸と第2軸の両方に表示中の系列があるときだけ第2軸を使う
+    fn secondary_axis(&self, values: &Values) -> Option<SecondaryAxis> {
+        if !self
+            .lines
+            .iter()
+            .any(|(_, c)| c.visible && c.secondary_axis)
+        {
+            return None;
+        }
+        Some(SecondaryAxis::fit(
+            self.y_extent(values, false)?,
+            self.y_extent(values, true)?,
+        ))
+    }
+
+    // 主軸に描画する表示中の系列の値の 2〜98 パーセンタイル
+    fn robust_y_range(
+        &self,
+        values: &Values,
+        secondary_axis: Option<SecondaryAxis>,
+    ) -> Option<(f64, f64)> {
+        let mut ys: Vec<f64> = self
+            .visible_keys()
+            .filter(|k| !self.on_secondary_axis(k, secondary_axis))
+            .filter_map(|k| self.line_points(values, k))
+            .flatten()
+            .filter_map(|[_, y]| self.y_scale.apply(y))
+            .collect();
+        if ys.is_empty() {
+            return None;
+        }
+        ys.sort_by(f64::total_cmp);
+        let percentile = |p: f64| ys[((ys.len() - 1) as f64 * p).round() as usize];
+        let (min, max) = (percentile(0.02), percentile(0.98));
+        let margin = ((max - min) * 0.05).max(f64::EPSILON);
+        Some((min - margin, max + margin))
+    }
+
+    // カーソル位置に最も近い各系列の値
+    fn values_at(&self, values: &Values, time: f64) -> Measurement {
+        let mut measurement = Measurement {
+            time,
+            values: Default::default(),
+        };
+        for k in self.visible_keys() {
+            let nearest = self.line_points(values, k).and_then(|points| {
+                points
+                    .into_iter()
+                    .min_by(|a, b| (a[0] - time).abs().total_cmp(&(b[0] - time).abs()))
+            });
+            if let Some([_, v]) = nearest {
+                measurement.values.insert(k.to_owned(), v);
+            }
+        }
+        measurement
+    }
+
+    // スクリーンショットのうちグラフの部分を PNG として保存する
+    fn save_image(&mut self, image: &ColorImage) {
+        let bytes = match image_export::encode_png(image) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.file_error = Some(format!("Failed to encode image: {}", e));
+                return;
+            }
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let mut fd = FileDialog::save_file(None)
+                .default_filename(format!("{}.png", self.title))
+                .title("Save image");
+            fd.open();
+            self.image_data = Some(bytes);
+            self.image_dialog = Some(fd);
+        }
+        #[cfg(target_arch = "wasm32")]
+        image_export::download(&format!("{}.png", self.title), &bytes, "image/png");
+    }
+
+    // Measurements に追加するよう要求された値
+    pub fn take_capture_request(&mut self) -> Option<Measurement> {
+        self.capture_request.take()
+    }
+
+    pub fn set_marked_time(&mut self, time: Option<f64>) {
+        self.marked_time = time;
+    }
+
+    pub fn take_mark_request(&mut self) -> Option<Option<f64>> {
+        self.mark_request.take()
+    }
+
+    pub fn take_file_error(&mut self) -> Option<String> {
+        self.file_error.take()
+    }
+
+    fn copy_data(&self, ctx: &Context, values: &Values) {
+        let mut text = String::from("key\ttime\tvalue\n");
+        for k in self.visible_keys() {
+            if let Some(points) = self.line_points(values, k) {
+                for [t, v] in points {
+                    text.push_str(&format!("{}\t{}\t{}\n", k, t, v));
+                }
+            }
+        }
+        ctx.copy_text(text);
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn ref_lines_ui(&mut self, ui: &mut Ui) {
+        let mut delete = None;
+        for (i, ref_line) in self.ref_lines.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(if ref_line.vertical { "x =" } else { "y =" });
+                ui.add(egui::DragValue::new(&mut ref_line.value).speed(0.1));
+                ui.add(
+                    egui::TextEdit::singleline(&mut ref_line.label)
+                        .hint_text("Label")
+                        .desired_width(80.0),
+                );
+                ui.color_edit_button_srgba(&mut ref_line.color);
+                if ui.button("Remove").clicked() {
+                    delete = Some(i);
+                }
+            });
+        }
+        if let Some(i) = delete {
+            self.ref_lines.remove(i);
+        }
+        ui.horizontal(|ui| {
+            if ui.button("Add horizontal").clicked() {
+                self.ref_lines.push(RefLine::new(false));
+            }
+            if ui.button("Add vertical").clicked() {
+                self.ref_lines.push(RefLine::new(true));
+            }
+        });
+    }
+
+    // 線ごとの表示名と色の設定
+    fn line_settings_ui(&mut self, ui: &mut Ui) {
+        egui::Grid::new(self.id.with("line_settings"))
+            .num_columns(4)
+            .show(ui, |ui| {
+                for (key, config) in self.lines.iter_mut().filter(|(_, c)| c.visible) {
+                    ui.label(key);
+                    let mut alias = config.alias.clone().unwrap_or_default();
+                    if ui
+                        .add(egui::TextEdit::singleline(&mut alias).hint_text(key))
+                        .changed()
+                    {
+                        config.alias = (!alias.is_empty()).then_some(alias);
+                    }
+                    ui.horizontal(|ui| {
+                        let mut custom_color = config.color.is_some();
+                        if ui.checkbox(&mut custom_color, "Color").changed() {
+                            config.color = custom_color.then_some(Color32::LIGHT_BLUE);
+                        }
+                        if let Some(color) = config.color.as_mut() {
+                            ui.color_edit_button_srgba(color);
+                        }
+                    });
+                    let transforms = [
+                        ("None", Transform::None),
+                        ("Derivative", Transform::Derivative),
+                        ("Integral", Transform::Integral),
+                    ];
+                    egui::ComboBox::from_id_salt(self.id.with(("transform", key)))
+                        .selected_text(
+                            transforms
+                                .iter()
+                                .find(|(_, t)| *t == config.transform)
+                                .unwrap()
+                                .0,
+                        )
+                        .show_ui(ui, |ui| {
+                            for (label, transform) in transforms {
+                                ui.selectable_value(&mut config.transform, transform, label);
+                            }
+                        });
+                    ui.end_row();
+                }
+            });
+    }
+
     pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
-        egui::Window::new(&self.title)
-            .id(self.id)
+        super::window(ctx, &self.title, self.id)
             .default_size(vec2(400.0, 600.0))
             .vscroll(false)
             .open(open)
@@ -88,67 +967,616 @@ impl LineGraph {
     }
 
     pub fn ui(&mut self, ui: &mut Ui, values: &Values) {
+        let query = super::key_filter_ui(ui, self.id.with("key_filter"));
         ScrollArea::horizontal()
             .id_salt(self.id.with("header"))
             .show(ui, |ui| {
                 ui.horizontal(|ui| {
-                    for key in values.keys() {
-                        if ui.selectable_label(self.keys.contains(key), key).clicked() {
-                            if let Some(index) = self.keys.iter().position(|k| k == key) {
-                                self.keys.remove(index);
-                            } else {
-                                self.keys.push(key.to_owned());
+                    // グラフに追加済みのキーは絞り込んでも残す
+                    let keys: Vec<_> = values
+                        .keys()
+                        .filter(|k| self.lines.contains_key(k) || super::key_matches(k, &query))
+                        .collect();
+                    for key in keys {
+                        // 追加済みのキーはチェックボックスで表示/非表示を切り替える
+                        if let Some(config) = self.lines.get_mut(key) {
+                            if ui
+                                .checkbox(&mut config.visible, "")
+                                .on_hover_text("Show in graph")
+                                .changed()
+                            {
+                                self.update_title();
                             }
-                            self.title = self.keys.join(", ");
+                        }
+                        let response = ui.selectable_label(self.lines.contains_key(key), key);
+                        if response.clicked() && !self.remove_key(key) {
+                            self.add_key(key.clone());
+                        }
+                        if self.lines.contains_key(key) {
+                            response.context_menu(|ui| {
+                                let label = if self.is_visible(key) { "Hide" } else { "Show" };
+                                if ui.button(label).clicked() {
+                                    self.toggle_visible(key);
+                                    ui.close_menu();
+                                }
+                                if let Some(config) = self.lines.get_mut(key) {
+                                    ui.menu_button("Change color", |ui| {
+                                        let mut custom_color = config.color.is_some();
+                                        if ui.checkbox(&mut custom_color, "Custom color").changed()
+                                        {
+                                            config.color =
+                                                custom_color.then_some(Color32::LIGHT_BLUE);
+                                        }
+                                        if let Some(color) = config.color.as_mut() {
+                                            egui::color_picker::color_picker_color32(
+                                                ui,
+                                                color,
+                                                egui::color_picker::Alpha::Opaque,
+                                            );
+                                        }
+                                    });
+                                    let label = if config.secondary_axis {
+                                        "Move to main Y axis"
+                                    } else {
+                                        "Move to other Y axis"
+                                    };
+                                    if ui.button(label).clicked() {
+                                        config.secondary_axis = !config.secondary_axis;
+                                        ui.close_menu();
+                                    }
+                                    ui.horizontal(|ui| {
+                                        ui.label("Median filter");
+                                        ui.add(
+                                            egui::DragValue::new(&mut config.median_window)
+                                                .range(0..=255)
+                                                .suffix(" samples"),
+                                        );
+                                    });
+                                }
+                                if ui.button("Remove").clicked() {
+                                    self.remove_key(key);
+                                    ui.close_menu();
+                                }
+                            });
                         }
                     }
                 });
             });
+        let jump_to_now = ui
+            .horizontal(|ui| {
+                ui.toggle_value(&mut self.show_line_settings, "Lines");
+                if ui.button("Save image").clicked() {
+                    ui.ctx()
+                        .send_viewport_cmd(egui::ViewportCommand::Screenshot(egui::UserData::new(
+                            self.id,
+                        )));
+                }
+                let jump_to_now = !self.following && ui.button("Jump to now").clicked();
+                let skipped: Vec<_> = self
+                    .visible_keys()
+                    .map(|k| (k, self.non_finite_count(values, k)))
+                    .filter(|(_, count)| *count > 0)
+                    .collect();
+                if !skipped.is_empty() {
+                    let total: usize = skipped.iter().map(|(_, count)| count).sum();
+                    ui.weak(format!("{} non-finite skipped", total))
+                        .on_hover_ui(|ui| {
+                            for (key, count) in skipped {
+                                ui.label(format!("{}: {}", key, count));
+                            }
+                        });
+                }
+                jump_to_now
+            })
+            .inner;
+        if self.show_line_settings {
+            self.line_settings_ui(ui);
+        }
         ui.separator();
-        Plot::new(self.id.with("plot"))
-            .legend(Legend::default().position(self.legend_position.into()))
+        // 非表示の系列も凡例には薄く残す (凡例のチェックで表示を戻せる)
+        let plot_id = self.id.with("plot");
+        let mut hidden_items = PlotMemory::load(ui.ctx(), plot_id)
+            .map(|m| m.hidden_items)
+            .unwrap_or_default();
+        for (key, config) in self.lines.iter() {
+            let name = config.display_name(key);
+            if config.visible {
+                hidden_items.remove(&name);
+            } else {
+                hidden_items.insert(name);
+            }
+        }
+        // 表示中の系列の単位を Y 軸に出す (違う単位が混ざっていれば並べる)
+        let secondary_axis = self.secondary_axis(values);
+        let axis_label = |secondary: bool| {
+            let mut units: Vec<_> = self
+                .visible_keys()
+                .filter(|k| self.on_secondary_axis(k, secondary_axis) == secondary)
+                .filter_map(|k| values.unit(k))
+                .collect();
+            units.sort();
+            units.dedup();
+            let units = units.join(", ");
+            match self.y_scale {
+                YScale::Linear => units,
+                YScale::Log10 if units.is_empty() => "log10".to_owned(),
+                YScale::Log10 => format!("log10 {}", units),
+            }
+        };
+        let mut plot = Plot::new(plot_id);
+        if let Some(axis) = secondary_axis {
+            // 主軸の設定は先頭の軸に掛かるので、第2軸は後ろに置く
+            plot = plot.custom_y_axes(vec![
+                AxisHints::new_y(),
+                axis.hints(
+                    self.y_scale,
+                    self.y_axis_position.opposite(),
+                    axis_label(true),
+                ),
+            ]);
+        }
+        let mut plot = plot
+            .legend(
+                Legend::default()
+                    .position(self.legend_position.into())
+                    .hidden_items(hidden_items),
+            )
             .x_axis_position(self.x_axis_position.into())
             .y_axis_position(self.y_axis_position.into())
             .y_axis_min_width(5.0)
             .show_axes(true)
-            .show_grid(true)
-            .show(ui, |ui| {
-                for k in &self.keys {
-                    if let Some(iter) = values.iter_for_key(k) {
-                        let skip = iter.len().saturating_sub(self.period);
-                        let iter = iter.skip(skip);
-                        let len = iter.len();
-                        let line = Line::new(PlotPoints::from_iter(
-                            iter.enumerate()
-                                .map(|(c, v)| [(c as f64 - len as f64) / 60.0, *v as f64]),
-                        ))
-                        .name(k);
-                        ui.line(line);
+            .show_grid(true);
+        plot = self.y_scale.apply_to_plot(plot);
+        let label = axis_label(false);
+        if !label.is_empty() {
+            plot = plot.y_axis_label(label);
+        }
+        if self.fixed_x_window {
+            plot = plot
+                .include_x(-(self.period as f64) / values.sample_rate() as f64)
+                .include_x(0.0);
+        }
+        let manual_y_range = self
+            .y_range
+            .manual()
+            .and_then(|(min, max)| Some((self.y_scale.apply(min)?, self.y_scale.apply(max)?)));
+        let locked;
+        (plot, locked) = lock_plot_axes(plot, None, manual_y_range);
+        let robust_y_range = if self.robust_y_bounds && !locked.y {
+            self.robust_y_range(values, secondary_axis)
+        } else {
+            None
+        };
+        if let Some((min, max)) = robust_y_range {
+            // y 方向はデータに合わせず include_y の範囲をそのまま使う
+            plot = plot
+                .auto_bounds(Vec2b::new(true, false))
+                .include_y(min)
+                .include_y(max);
+        }
+        let plot_top_left = ui.cursor().min;
+        let mut readout = vec![];
+        let response = plot.show(ui, |ui| {
+            if jump_to_now || (robust_y_range.is_some() && ui.auto_bounds().any()) {
+                ui.set_auto_bounds(true.into());
+            }
+            keep_axes_locked(ui, locked);
+            let pointer = ui.pointer_coordinate();
+            // x を自動で合わせているときは全体が見えているので絞り込まない
+            let x_range = (!ui.auto_bounds().x).then(|| ui.plot_bounds().range_x());
+            let buckets = if self.downsampling {
+                ui.response().rect.width() as usize
+            } else {
+                0
+            };
+            // tick カウンタが飛んだところでは線をつながない
+            let now = values.latest_time().unwrap_or(0.0);
+            let gaps: Vec<f64> = values.gaps().iter().map(|g| g.time - now).collect();
+            // 線を分けても色が揃うように自動の色はここで割り当てる
+            let colors = values.line_colors();
+            let mut color_index = 0;
+            let mut drawn = vec![];
+            let y_scale = self.y_scale;
+            // 描画する y 座標に変換する
+            let plot_points = |config: &LineConfig, points| {
+                let points = y_scale.apply_points(points);
+                match secondary_axis.filter(|_| config.secondary_axis) {
+                    Some(axis) => axis.apply_points(points),
+                    None => points,
+                }
+            };
+            for (k, config) in self.lines.iter() {
+                if !config.visible {
+                    if values.contains_key(k) {
+                        let color = config
+                            .color
+                            .unwrap_or_else(|| next_color(&colors, &mut color_index));
+                        ui.line(config.line(k, vec![], color));
+                        drawn.push(k.clone());
                     }
+                    continue;
+                }
+                if let Some(raw_points) = self.raw_line_points(values, k) {
+                    drawn.push(k.clone());
+                    let median = (config.median_window > 1)
+                        .then(|| rolling_median(&raw_points, config.median_window));
+                    let points = self.smooth(raw_points);
+                    if let Some(v) = pointer
+                        .filter(|_| self.show_readout)
+                        .and_then(|p| interpolate(&points, p.x))
+                    {
+                        // 微分や積分した値は整数チャンネルでも小数で表示する
+                        let value = if config.transform == Transform::None {
+                            values.format_value(k, v)
+                        } else {
+                            values.number_format().format_float(v)
+                        };
+                        readout.push(format!("{}: {}", config.display_name(k), value));
+                    }
+                    if let Some(median) = median {
+                        let median = downsample(median, x_range.clone(), buckets);
+                        let color = next_color(&colors, &mut color_index);
+                        let name = format!(
+                            "{} (median {})",
+                            config.display_name(k),
+                            config.median_window
+                        );
+                        for segment in split_at_gaps(plot_points(config, median), &gaps) {
+                            ui.line(
+                                Line::new(PlotPoints::from(segment))
+                                    .name(&name)
+                                    .color(color),
+                            );
+                        }
+                    }
+                    let points = downsample(points, x_range.clone(), buckets);
+                    let color = config
+                        .color
+                        .unwrap_or_else(|| next_color(&colors, &mut color_index));
+                    for segment in split_at_gaps(plot_points(config, points), &gaps) {
+                        ui.line(config.line(k, segment, color));
+                    }
+                }
+            }
+            for ref_line in &self.ref_lines {
+                if ref_line.vertical {
+                    ui.vline(
+                        VLine::new(ref_line.value)
+                            .color(ref_line.color)
+                            .name(&ref_line.label),
+                    );
+                } else if let Some(y) = self.y_scale.apply(ref_line.value) {
+                    ui.hline(HLine::new(y).color(ref_line.color).name(&ref_line.label));
+                }
+            }
+            if let Some(pointer) = pointer {
+                self.cursor = Some(pointer.x);
+            }
+            if let Some(x) = self.cursor {
+                ui.vline(VLine::new(x).color(ui.ctx().style().visuals.weak_text_color()));
+            }
+            if let Some(time) = self.marked_time {
+                ui.vline(
+                    VLine::new(time - now)
+                        .color(ui.ctx().style().visuals.selection.bg_fill)
+                        .width(2.0_f32),
+                );
+            }
+            // y を固定している間は x だけで追従中かを判断する
+            (jump_to_now || ui.auto_bounds().and(!locked).any(), drawn)
+        });
+        let (following, drawn) = response.inner;
+        // Ctrl (Mac では Cmd) を押しながらクリックした時刻に印を付ける
+        let now = values.latest_time().unwrap_or(0.0);
+        if response.response.clicked() && ui.input(|i| i.modifiers.command) {
+            if let Some(x) = self.cursor {
+                self.mark_request = Some(Some(x + now));
+            }
+        }
+        // 凡例のチェックで切り替えられた表示状態を取り込む
+        if let Some(memory) = PlotMemory::load(ui.ctx(), plot_id) {
+            let mut changed = false;
+            for key in drawn {
+                if let Some(config) = self.lines.get_mut(&key) {
+                    let visible = !memory.hidden_items.contains(&config.display_name(&key));
+                    changed |= config.visible != visible;
+                    config.visible = visible;
+                }
+            }
+            if changed {
+                self.update_title();
+            }
+        }
+        let y_bounds = response.transform.bounds().range_y();
+        let y_bounds = (
+            self.y_scale.invert(*y_bounds.start()),
+            self.y_scale.invert(*y_bounds.end()),
+        );
+        self.following = following;
+        if let (Some(x), false) = (self.cursor, readout.is_empty()) {
+            if response.response.hovered() {
+                response.response.clone().on_hover_ui_at_pointer(|ui| {
+                    ui.label(format!("{:.3} s", x));
+                    for line in &readout {
+                        ui.label(line);
+                    }
+                });
+            }
+        }
+        // 軸を含めたグラフ全体の範囲
+        let plot_rect = Rect::from_min_max(plot_top_left, ui.min_rect().max);
+        let screenshot = ui.ctx().input(|i| {
+            i.raw.events.iter().find_map(|e| match e {
+                egui::Event::Screenshot {
+                    user_data, image, ..
+                } if user_data.data.as_ref().and_then(|d| d.downcast_ref::<Id>())
+                    == Some(&self.id) =>
+                {
+                    Some(image.clone())
+                }
+                _ => None,
+            })
+        });
+        if let Some(image) = screenshot {
+            // 画面外にはみ出した部分は切り捨てる
+            let pixels_per_point = ui.ctx().pixels_per_point();
+            let screen_rect = Rect::from_min_size(
+                egui::Pos2::ZERO,
+                vec2(image.width() as f32, image.height() as f32) / pixels_per_point,
+            );
+            let rect = plot_rect.intersect(screen_rect);
+            if rect.is_positive() {
+                self.save_image(&image.region(&rect, Some(pixels_per_point)));
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(image_dialog) = self.image_dialog.as_mut() {
+            if image_dialog.show(ui.ctx()).selected() {
+                if let (Some(path), Some(bytes)) = (image_dialog.path(), self.image_data.as_ref()) {
+                    if let Err(e) = std::fs::write(path, bytes) {
+                        self.file_error = Some(format!("Failed to save image: {}", e));
+                    }
+                }
+                self.image_data = None;
+                self.image_dialog = None;
+            }
+        }
+        let sample_rate = values.sample_rate();
+        response.response.context_menu(|ui| {
+            graph_context_menu(
+                ui,
+                &mut self.legend_position,
+                &mut self.x_axis_position,
+                &mut self.y_axis_position,
+                &mut self.y_scale,
+                &mut self.period,
+                sample_rate,
+            );
+            ui.menu_button("Y Range", |ui| self.y_range.ui(ui, y_bounds));
+            if ui
+                .checkbox(&mut self.fixed_x_window, "Fixed width scrolling")
+                .clicked()
+            {
+                ui.close_menu();
+            }
+            ui.menu_button("Reference lines", |ui| self.ref_lines_ui(ui));
+            ui.menu_button("Smoothing", |ui| {
+                let mut clicked = false;
+                for (label, window) in [
+                    ("None", 0),
+                    ("3 samples", 3),
+                    ("5 samples", 5),
+                    ("15 samples", 15),
+                    ("60 samples", 60),
+                ] {
+                    clicked |= ui.radio_value(&mut self.smoothing, window, label).clicked();
+                }
+                if clicked {
+                    ui.close_menu();
+                }
+            });
+            if ui
+                .checkbox(&mut self.robust_y_bounds, "Ignore outliers in Y range")
+                .clicked()
+            {
+                ui.close_menu();
+            }
+            if ui
+                .checkbox(&mut self.show_readout, "Show values at cursor")
+                .clicked()
+            {
+                ui.close_menu();
+            }
+            if ui
+                .checkbox(&mut self.downsampling, "Downsample for drawing")
+                .clicked()
+            {
+                ui.close_menu();
+            }
+            if ui.button("Copy data").clicked() {
+                self.copy_data(ui.ctx(), values);
+                ui.close_menu();
+            }
+            if let Some(x) = self.cursor {
+                if ui.button("Capture values at cursor").clicked() {
+                    self.capture_request = Some(self.values_at(values, x));
+                    ui.close_menu();
+                }
+                if ui.button("Mark this time").clicked() {
+                    self.mark_request = Some(Some(x + now));
+                    ui.close_menu();
                 }
+            }
+            if self.marked_time.is_some() && ui.button("Clear mark").clicked() {
+                self.mark_request = Some(None);
+                ui.close_menu();
+            }
+        });
+    }
+}
+
+// XY グラフの系列の描き方
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum XYMode {
+    #[default]
+    Line,
+    Points,
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum MarkerShape {
+    #[default]
+    Circle,
+    Square,
+    Diamond,
+    Cross,
+    Plus,
+}
+
+impl From<MarkerShape> for egui_plot::MarkerShape {
+    fn from(s: MarkerShape) -> Self {
+        match s {
+            MarkerShape::Circle => egui_plot::MarkerShape::Circle,
+            MarkerShape::Square => egui_plot::MarkerShape::Square,
+            MarkerShape::Diamond => egui_plot::MarkerShape::Diamond,
+            MarkerShape::Cross => egui_plot::MarkerShape::Cross,
+            MarkerShape::Plus => egui_plot::MarkerShape::Plus,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+struct XYStyle {
+    mode: XYMode,
+    marker: MarkerShape,
+    radius: f32,
+}
+
+impl Default for XYStyle {
+    fn default() -> Self {
+        Self {
+            mode: XYMode::Line,
+            marker: MarkerShape::Circle,
+            radius: 2.0,
+        }
+    }
+}
+
+impl XYStyle {
+    fn ui(&mut self, ui: &mut Ui, id: Id) {
+        egui::ComboBox::from_id_salt(id.with("mode"))
+            .width(60.0)
+            .selected_text(match self.mode {
+                XYMode::Line => "Line",
+                XYMode::Points => "Points",
+                XYMode::Both => "Both",
             })
-            .response
-            .context_menu(|ui| {
-                graph_context_menu(
-                    ui,
-                    &mut self.legend_position,
-                    &mut self.x_axis_position,
-                    &mut self.y_axis_position,
-                    &mut self.period,
-                )
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.mode, XYMode::Line, "Line");
+                ui.selectable_value(&mut self.mode, XYMode::Points, "Points");
+                ui.selectable_value(&mut self.mode, XYMode::Both, "Both");
+            });
+        if self.mode == XYMode::Line {
+            return;
+        }
+        let markers = [
+            ("Circle", MarkerShape::Circle),
+            ("Square", MarkerShape::Square),
+            ("Diamond", MarkerShape::Diamond),
+            ("Cross", MarkerShape::Cross),
+            ("Plus", MarkerShape::Plus),
+        ];
+        egui::ComboBox::from_id_salt(id.with("marker"))
+            .width(60.0)
+            .selected_text(markers.iter().find(|(_, m)| *m == self.marker).unwrap().0)
+            .show_ui(ui, |ui| {
+                for (label, marker) in markers {
+                    ui.selectable_value(&mut self.marker, marker, label);
+                }
             });
+        ui.add(
+            egui::DragValue::new(&mut self.radius)
+                .range(0.5..=10.0)
+                .speed(0.1)
+                .prefix("r "),
+        );
+    }
+}
+
+// x キー、y キー、描き方
+// 以前の (x, y) のレイアウトも読めるようにタプルのままにしている
+#[derive(Serialize, Deserialize, Clone)]
+struct XYPair(String, String, #[serde(default)] XYStyle);
+
+// 自動の色を順に割り当てる
+fn next_color(colors: &LineColors, index: &mut usize) -> Color32 {
+    *index += 1;
+    colors.color(*index - 1)
+}
+
+// gaps (x 座標、昇順) をまたぐところで点列を分ける
+fn split_at_gaps(points: Vec<[f64; 2]>, gaps: &[f64]) -> Vec<Vec<[f64; 2]>> {
+    let mut segments = vec![];
+    let mut current = vec![];
+    let mut gaps = gaps.iter().peekable();
+    for point in points {
+        let mut split = false;
+        while let Some(gap) = gaps.peek() {
+            if **gap > point[0] {
+                break;
+            }
+            split = true;
+            gaps.next();
+        }
+        if split && !current.is_empty() {
+            segments.push(std::mem::take(&mut current));
+        }
+        current.push(point);
+    }
+    segments.push(current);
+    segments
+}
+
+// 古さで薄くするときの段階の数
+const FADE_STEPS: usize = 32;
+
+// len 点の列を古さ (period に対する割合) の段階ごとに分け、各区間の不透明度と一緒に返す
+fn fade_segments(len: usize, period: usize) -> Vec<(f32, Range<usize>)> {
+    let step = |i: usize| ((len - 1 - i) * FADE_STEPS / period.max(1)).min(FADE_STEPS - 1);
+    let mut segments = vec![];
+    let mut start = 0;
+    for i in 1..=len {
+        if i == len || step(i) != step(start) {
+            let alpha = 1.0 - step(start) as f32 / FADE_STEPS as f32;
+            segments.push((alpha, start..i));
+            start = i;
+        }
     }
+    segments
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct XYGraph {
     id: Id,
     selector: (String, String),
-    keys: Vec<(String, String)>,
+    keys: Vec<XYPair>,
     legend_position: Corner,
     x_axis_position: VPlacement,
     y_axis_position: HPlacement,
     period: usize,
+    #[serde(default)]
+    y_scale: YScale,
+    #[serde(default)]
+    x_range: AxisRange,
+    #[serde(default)]
+    y_range: AxisRange,
+    // 古いサンプルほど薄く描く
+    #[serde(default)]
+    fade_by_age: bool,
+    #[serde(skip, default = "super::default_following")]
+    following: bool,
 }
 
 impl XYGraph {
@@ -162,12 +1590,28 @@ impl XYGraph {
             x_axis_position: VPlacement::Bottom,
             y_axis_position: HPlacement::Left,
             period: 3600,
+            y_scale: YScale::Linear,
+            x_range: AxisRange::Auto,
+            y_range: AxisRange::Auto,
+            fade_by_age: false,
+            following: true,
         }
     }
 
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn title(&self) -> &str {
+        "XY Graph"
+    }
+
     pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
-        egui::Window::new("XY Graph")
-            .id(self.id)
+        super::window(ctx, self.title(), self.id)
             .default_size(vec2(400.0, 600.0))
             .vscroll(false)
             .open(open)
@@ -179,14 +1623,16 @@ impl XYGraph {
             egui::ComboBox::from_id_salt(self.id.with("x_selector"))
                 .selected_text(&self.selector.0)
                 .show_ui(ui, |ui| {
-                    for key in values.keys() {
+                    let query = super::key_filter_ui(ui, self.id.with("x_filter"));
+                    for key in super::filter_keys(values.keys(), &query) {
                         ui.selectable_value(&mut self.selector.0, key.to_owned(), key);
                     }
                 });
             egui::ComboBox::from_id_salt(self.id.with("y_selector"))
                 .selected_text(&self.selector.1)
                 .show_ui(ui, |ui| {
-                    for key in values.keys() {
+                    let query = super::key_filter_ui(ui, self.id.with("y_filter"));
+                    for key in super::filter_keys(values.keys(), &query) {
                         ui.selectable_value(&mut self.selector.1, key.to_owned(), key);
                     }
                 });
@@ -194,15 +1640,17 @@ impl XYGraph {
                 && values.contains_key(&self.selector.0)
                 && values.contains_key(&self.selector.1)
             {
-                self.keys.push(std::mem::take(&mut self.selector));
+                let (x, y) = std::mem::take(&mut self.selector);
+                self.keys.push(XYPair(x, y, XYStyle::default()));
             }
         });
         ui.separator();
         {
             let mut delete = None;
-            for (index, keys) in self.keys.iter().enumerate() {
+            for (index, XYPair(x_key, y_key, style)) in self.keys.iter_mut().enumerate() {
                 ui.horizontal(|ui| {
-                    ui.label(format!("{:5} {:5}", keys.0, keys.1));
+                    ui.label(format!("{:5} {:5}", x_key, y_key));
+                    style.ui(ui, self.id.with(("style", index)));
                     if ui.button("Remove").clicked() {
                         delete = Some(index);
                     }
@@ -212,44 +1660,105 @@ impl XYGraph {
                 self.keys.remove(index);
             }
         }
+        let jump_to_now = !self.following && ui.button("Jump to now").clicked();
         ui.separator();
-        Plot::new(self.id.with("plot"))
+        let mut plot = Plot::new(self.id.with("plot"))
             .legend(Legend::default().position(self.legend_position.into()))
             .x_axis_position(self.x_axis_position.into())
             .y_axis_position(self.y_axis_position.into())
             .y_axis_min_width(5.0)
             .show_axes(true)
-            .show_grid(true)
-            .data_aspect(1.0)
-            .show(ui, |ui| {
-                for (x_key, y_key) in &self.keys {
-                    if let (Some(x_iter), Some(y_iter)) =
-                        (values.iter_for_key(x_key), values.iter_for_key(y_key))
-                    {
-                        ui.line(
-                            Line::new(PlotPoints::from_iter(
-                                x_iter
-                                    .rev()
-                                    .zip(y_iter.rev())
-                                    .take(self.period)
-                                    .rev()
-                                    .map(|(x, y)| [*x as f64, *y as f64]),
-                            ))
-                            .name(format!("{} {}", x_key, y_key)),
-                        );
+            .show_grid(true);
+        let manual_y_range = self
+            .y_range
+            .manual()
+            .and_then(|(min, max)| Some((self.y_scale.apply(min)?, self.y_scale.apply(max)?)));
+        let locked;
+        (plot, locked) = lock_plot_axes(plot, self.x_range.manual(), manual_y_range);
+        // 範囲を固定した軸があるときは縦横比より固定した範囲を優先する
+        if !locked.any() {
+            plot = plot.data_aspect(1.0);
+        }
+        let response = self.y_scale.apply_to_plot(plot).show(ui, |ui| {
+            if jump_to_now {
+                ui.set_auto_bounds(true.into());
+            }
+            keep_axes_locked(ui, locked);
+            let colors = values.line_colors();
+            for (index, XYPair(x_key, y_key, style)) in self.keys.iter().enumerate() {
+                if let (Some(x_values), Some(y_values)) =
+                    (values.values_for_key(x_key), values.values_for_key(y_key))
+                {
+                    let points: Vec<[f64; 2]> = x_values
+                        .iter()
+                        .rev()
+                        .zip(y_values.iter().rev())
+                        .take(self.period)
+                        .rev()
+                        .filter(|(x, y)| x.is_finite() && y.is_finite())
+                        .filter_map(|(x, y)| Some([*x, self.y_scale.apply(*y)?]))
+                        .collect();
+                    let name = format!("{} {}", x_key, y_key);
+                    let color = colors.color(index);
+                    let segments = if self.fade_by_age {
+                        fade_segments(points.len(), self.period)
+                    } else {
+                        vec![(1.0, 0..points.len())]
+                    };
+                    for (alpha, range) in segments {
+                        let color = color.gamma_multiply(alpha);
+                        if style.mode != XYMode::Points {
+                            // 線が途切れないよう次の区間の最初の点までつなぐ
+                            let end = (range.end + 1).min(points.len());
+                            ui.line(
+                                Line::new(PlotPoints::from(points[range.start..end].to_vec()))
+                                    .name(&name)
+                                    .color(color),
+                            );
+                        }
+                        if style.mode != XYMode::Line {
+                            ui.points(
+                                Points::new(PlotPoints::from(points[range].to_vec()))
+                                    .shape(style.marker.into())
+                                    .radius(style.radius)
+                                    .filled(true)
+                                    .name(&name)
+                                    .color(color),
+                            );
+                        }
                     }
                 }
-            })
-            .response
-            .context_menu(|ui| {
-                graph_context_menu(
-                    ui,
-                    &mut self.legend_position,
-                    &mut self.x_axis_position,
-                    &mut self.y_axis_position,
-                    &mut self.period,
-                )
-            });
+            }
+            // 両方の軸を固定しているときは追従するものがない
+            jump_to_now || locked.all() || ui.auto_bounds().and(!locked).any()
+        });
+        self.following = response.inner;
+        let bounds = response.transform.bounds();
+        let x_bounds = (bounds.min()[0], bounds.max()[0]);
+        let y_bounds = (
+            self.y_scale.invert(bounds.min()[1]),
+            self.y_scale.invert(bounds.max()[1]),
+        );
+        let sample_rate = values.sample_rate();
+        response.response.context_menu(|ui| {
+            graph_context_menu(
+                ui,
+                &mut self.legend_position,
+                &mut self.x_axis_position,
+                &mut self.y_axis_position,
+                &mut self.y_scale,
+                &mut self.period,
+                sample_rate,
+            );
+            ui.menu_button("Y Range", |ui| self.y_range.ui(ui, y_bounds));
+            ui.menu_button("X Range", |ui| self.x_range.ui(ui, x_bounds));
+            if ui
+                .checkbox(&mut self.fade_by_age, "Fade older samples")
+                .clicked()
+            {
+                ui.close_menu();
+            }
+        });
     }
 }
 
@@ -258,7 +1767,9 @@ fn graph_context_menu(
     legend_position: &mut Corner,
     x_axis_position: &mut VPlacement,
     y_axis_position: &mut HPlacement,
+    y_scale: &mut YScale,
     period: &mut usize,
+    sample_rate: u32,
 ) {
     ui.menu_button("Legend", |ui| {
         let mut clicked = false;
@@ -292,16 +1803,26 @@ fn graph_context_menu(
             ui.close_menu();
         }
     });
+    ui.menu_button("Y Scale", |ui| {
+        let mut clicked = false;
+        for (label, scale) in [("Linear", YScale::Linear), ("Log10", YScale::Log10)] {
+            clicked |= ui.radio_value(y_scale, scale, label).clicked();
+        }
+        if clicked {
+            ui.close_menu();
+        }
+    });
     ui.menu_button("Period", |ui| {
         let mut clicked = false;
-        for (label, p) in [
-            ("10sec", 60 * 10),
-            ("1min", 60 * 60),
-            ("5min", 60 * 60 * 5),
-            ("10min", 60 * 60 * 10),
-            ("15min", 60 * 60 * 15),
-            ("30min", 60 * 60 * 30),
+        for (label, seconds) in [
+            ("10sec", 10),
+            ("1min", 60),
+            ("5min", 60 * 5),
+            ("10min", 60 * 10),
+            ("15min", 60 * 15),
+            ("30min", 60 * 30),
         ] {
+            let p = seconds * sample_rate as usize;
             clicked |= ui.radio_value(period, p, label).clicked();
         }
         if clicked {
@@ -309,3 +1830,107 @@ fn graph_context_menu(
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    #[test]
+    fn old_keys_load_in_order() {
+        let mut value = serde_json::to_value(LineGraph::new("graph", "a".to_owned())).unwrap();
+        let object = value.as_object_mut().unwrap();
+        object.remove("lines");
+        object.insert("keys".to_owned(), serde_json::json!(["b", "a"]));
+
+        let graph: LineGraph = serde_json::from_value(value).unwrap();
+        assert_eq!(graph.visible_keys().collect::<Vec<_>>(), ["b", "a"]);
+    }
+
+    #[test]
+    fn smoothing_applies_to_copied_and_captured_values() {
+        let mut values = Values::new(Rc::new(RefCell::new(Settings::default())));
+        values.add_data(
+            HashMap::from([("a".to_owned(), vec![0.0, 3.0, 6.0, 30.0])]),
+            None,
+        );
+        let mut graph = LineGraph::new("graph", "a".to_owned());
+        graph.smoothing = 3;
+
+        let ys: Vec<f64> = graph
+            .line_points(&values, "a")
+            .unwrap()
+            .into_iter()
+            .map(|[_, y]| y)
+            .collect();
+        assert_eq!(ys, [1.5, 3.0, 13.0, 18.0]);
+        // カーソル位置の値も描画した線の値になる
+        assert_eq!(graph.values_at(&values, 0.0).values["a"], 18.0);
+    }
+
+    #[test]
+    fn secondary_axis_overlays_the_main_axis_range() {
+        let axis = SecondaryAxis::fit((0.0, 10.0), (100.0, 200.0));
+        assert_eq!(
+            axis.apply_points(vec![[0.0, 100.0], [1.0, 150.0], [2.0, 200.0]]),
+            [[0.0, 0.0], [1.0, 5.0], [2.0, 10.0]]
+        );
+        // 目盛りには第2軸の値を出す
+        assert_eq!(axis.invert(5.0), 150.0);
+
+        // 値が一定の系列は倍率を変えずに主軸の最小値に重ねる
+        let axis = SecondaryAxis::fit((0.0, 10.0), (3.0, 3.0));
+        assert_eq!(axis.apply_points(vec![[0.0, 3.0]]), [[0.0, 0.0]]);
+        assert_eq!(axis.invert(1.0), 4.0);
+    }
+
+    #[test]
+    fn lines_keep_insertion_order() {
+        let mut graph = LineGraph::new("graph", "b".to_owned());
+        graph.add_key("a".to_owned());
+        graph.toggle_visible("b");
+
+        let json = serde_json::to_string(&graph).unwrap();
+        let graph: LineGraph = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            graph
+                .lines
+                .iter()
+                .map(|(k, c)| (k.as_str(), c.visible))
+                .collect::<Vec<_>>(),
+            [("b", false), ("a", true)]
+        );
+        assert_eq!(graph.title, "a");
+    }
+
+    #[test]
+    fn removed_lines_keep_their_config() {
+        let mut graph = LineGraph::new("graph", "a".to_owned());
+        graph.add_key("b".to_owned());
+        let config = graph.lines.get_mut("a").unwrap();
+        config.alias = Some("speed".to_owned());
+        config.color = Some(Color32::RED);
+        config.transform = Transform::Derivative;
+        config.median_window = 5;
+        config.secondary_axis = true;
+
+        assert!(graph.remove_key("a"));
+        assert!(!graph.lines.contains_key("a"));
+        assert_eq!(graph.title, "b");
+
+        // 外した設定も保存され、追加し直すと戻る
+        let json = serde_json::to_string(&graph).unwrap();
+        let mut graph: LineGraph = serde_json::from_str(&json).unwrap();
+        graph.add_key("a".to_owned());
+        let config = graph.lines.get("a").unwrap();
+        assert!(config.visible);
+        assert_eq!(config.alias.as_deref(), Some("speed"));
+        assert_eq!(config.color, Some(Color32::RED));
+        assert!(config.transform == Transform::Derivative);
+        assert_eq!(config.median_window, 5);
+        assert!(config.secondary_axis);
+        assert!(!graph.removed_lines.contains_key("a"));
+        assert_eq!(graph.visible_keys().collect::<Vec<_>>(), ["b", "a"]);
+    }
+}
@@ -0,0 +1,38 @@
+use egui::ColorImage;
+
+// 画像を PNG にエンコードする
+pub fn encode_png(image: &ColorImage) -> Result<Vec<u8>, png::EncodingError> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder =
+            png::Encoder::new(&mut bytes, image.width() as u32, image.height() as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(image.as_raw())?;
+    }
+    Ok(bytes)
+}
+
+// ブラウザのダウンロードとして保存させる
+#[cfg(target_arch = "wasm32")]
+pub fn download(filename: &str, bytes: &[u8], mime: &str) -> Option<()> {
+    use eframe::wasm_bindgen::JsCast;
+
+    let parts = js_sys::Array::new();
+    parts.push(&js_sys::Uint8Array::from(bytes));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(mime);
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options).ok()?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).ok()?;
+    let anchor = web_sys::window()?
+        .document()?
+        .create_element("a")
+        .ok()?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .ok()?;
+    anchor.set_href(&url);
+    anchor.set_download(filename);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url).ok()
+}
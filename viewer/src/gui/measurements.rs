@@ -0,0 +1,123 @@
+use egui::{vec2, Context, Id, Ui};
+use egui_extras::{Column, TableBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+// グラフのカーソル位置で取り込んだ値
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Measurement {
+    // 取り込んだ時点を 0 とした秒
+    pub time: f64,
+    pub values: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Measurements {
+    rows: Vec<Measurement>,
+}
+
+impl Measurements {
+    pub fn push(&mut self, measurement: Measurement) {
+        self.rows.push(measurement);
+    }
+
+    fn keys(&self) -> BTreeSet<&String> {
+        self.rows.iter().flat_map(|r| r.values.keys()).collect()
+    }
+
+    pub fn save_csv(&self, path: &Path) -> Result<(), std::io::Error> {
+        let keys = self.keys();
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all("time".as_bytes())?;
+        for key in &keys {
+            writer.write_fmt(format_args!(",{}", key))?;
+        }
+        writer.write_all("\n".as_bytes())?;
+        for row in &self.rows {
+            writer.write_fmt(format_args!("{}", row.time))?;
+            for key in &keys {
+                match row.values.get(*key) {
+                    Some(v) => writer.write_fmt(format_args!(",{}", v))?,
+                    None => writer.write_all(",".as_bytes())?,
+                }
+            }
+            writer.write_all("\n".as_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn show(&mut self, ctx: &Context, open: &mut bool) -> bool {
+        let mut export = false;
+        egui::Window::new("Measurements")
+            .id(Id::new("measurements"))
+            .default_size(vec2(300.0, 200.0))
+            .open(open)
+            .show(ctx, |ui| export = self.ui(ui));
+        export
+    }
+
+    // "Export CSV" が押されたら true を返す
+    fn ui(&mut self, ui: &mut Ui) -> bool {
+        let export = ui
+            .horizontal(|ui| {
+                #[cfg(not(target_arch = "wasm32"))]
+                let export = ui.button("Export CSV").clicked();
+                #[cfg(target_arch = "wasm32")]
+                let export = false;
+                if ui.button("Clear").clicked() {
+                    self.rows.clear();
+                }
+                export
+            })
+            .inner;
+        ui.separator();
+
+        let keys = self.keys();
+        let mut delete = None;
+        TableBuilder::new(ui)
+            .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+            .column(Column::auto())
+            .columns(Column::auto().at_least(60.0), keys.len() + 1)
+            .header(20.0, |mut header| {
+                header.col(|_| {});
+                header.col(|ui| {
+                    ui.strong("Time");
+                });
+                for key in &keys {
+                    header.col(|ui| {
+                        ui.strong(*key);
+                    });
+                }
+            })
+            .body(|body| {
+                body.rows(20.0, self.rows.len(), |mut row| {
+                    let index = row.index();
+                    let measurement = &self.rows[index];
+                    row.col(|ui| {
+                        if ui.small_button("x").clicked() {
+                            delete = Some(index);
+                        }
+                    });
+                    row.col(|ui| {
+                        ui.label(format!("{:.3}", measurement.time));
+                    });
+                    for key in &keys {
+                        row.col(|ui| {
+                            if let Some(v) = measurement.values.get(*key) {
+                                ui.label(v.to_string());
+                            }
+                        });
+                    }
+                });
+            });
+        if let Some(index) = delete {
+            self.rows.remove(index);
+        }
+        export
+    }
+}
@@ -3,3 +3,98 @@ mod table;
 mod graph;
 mod digital_table;
 mod nits_timeline;
+mod measurements;
+mod overview;
+mod image_export;
+
+use crate::values::Values;
+use egui::{scroll_area::ScrollAreaOutput, Context, Id, Pos2, Ui, WidgetText};
+
+fn default_following() -> bool {
+    true
+}
+
+// データを表示するウィンドウ (テンプレートから開いたときは保存されていた位置に置く)
+fn window<'open>(ctx: &Context, title: impl Into<WidgetText>, id: Id) -> egui::Window<'open> {
+    let window = egui::Window::new(title).id(id);
+    match ctx.data_mut(|d| d.remove_temp::<Pos2>(id.with("initial_pos"))) {
+        Some(pos) => window.current_pos(pos),
+        None => window,
+    }
+}
+
+fn set_initial_window_pos(ctx: &Context, id: Id, pos: Pos2) {
+    ctx.data_mut(|d| d.insert_temp(id.with("initial_pos"), pos));
+}
+
+// スクロール位置が末尾 (最新の値) にあるかどうか
+fn is_scrolled_to_bottom<R>(output: &ScrollAreaOutput<R>) -> bool {
+    output.state.offset.y + output.inner_rect.height() >= output.content_size.y - 1.0
+}
+
+// ウィンドウごとの自動スクロールの上書き (None なら全体の設定に従う)
+fn auto_scroll_ui(ui: &mut Ui, id: Id, auto_scroll: &mut Option<bool>) {
+    let label = |v: Option<bool>| match v {
+        None => "Auto-scroll: Default",
+        Some(true) => "Auto-scroll: On",
+        Some(false) => "Auto-scroll: Off",
+    };
+    egui::ComboBox::from_id_salt(id.with("auto_scroll"))
+        .selected_text(label(*auto_scroll))
+        .show_ui(ui, |ui| {
+            for v in [None, Some(true), Some(false)] {
+                ui.selectable_value(auto_scroll, v, label(v));
+            }
+        });
+}
+
+fn auto_scroll(auto_scroll: Option<bool>, values: &Values) -> bool {
+    auto_scroll.unwrap_or_else(|| values.auto_scroll())
+}
+
+// key が query を大文字小文字を区別せずに含むか (query が空なら常に true)
+fn key_matches(key: &str, query: &str) -> bool {
+    query.is_empty() || key.to_lowercase().contains(&query.to_lowercase())
+}
+
+fn filter_keys<'a>(
+    keys: impl Iterator<Item = &'a String>,
+    query: &'a str,
+) -> impl Iterator<Item = &'a String> {
+    keys.filter(move |k| key_matches(k, query))
+}
+
+// キーを絞り込む文字列の入力欄 (入力中の文字列は id ごとに egui のメモリに置く)
+fn key_filter_ui(ui: &mut Ui, id: Id) -> String {
+    let mut query = ui.data_mut(|d| d.get_temp::<String>(id).unwrap_or_default());
+    ui.add(
+        egui::TextEdit::singleline(&mut query)
+            .hint_text("Filter keys")
+            .desired_width(120.0),
+    );
+    ui.data_mut(|d| d.insert_temp(id, query.clone()));
+    query
+}
+
+// 表の値のセルの右クリックメニュー (row は行全体をタブ区切りにしたもの)
+fn cell_context_menu(
+    response: &egui::Response,
+    raw: f64,
+    formatted: &str,
+    row: impl FnOnce() -> String,
+) {
+    response.context_menu(|ui| {
+        if ui.button("Copy value").clicked() {
+            ui.ctx().copy_text(raw.to_string());
+            ui.close_menu();
+        }
+        if ui.button("Copy formatted value").clicked() {
+            ui.ctx().copy_text(formatted.to_owned());
+            ui.close_menu();
+        }
+        if ui.button("Copy row").clicked() {
+            ui.ctx().copy_text(row());
+            ui.close_menu();
+        }
+    });
+}
@@ -1,23 +1,31 @@
 use crate::{
-    nits::{NitsCommand, NitsCommandType, NitsSender},
+    nits::{nits_channel_key, NitsCommand, NitsCommandType, NitsSender},
     values::Values,
 };
-use egui::{vec2, Checkbox, Context, Id, Layout, RichText, Ui};
+use egui::{
+    scroll_area::ScrollBarVisibility, vec2, Checkbox, Color32, Context, Id, Label, Layout,
+    RichText, ScrollArea, Sense, Ui,
+};
 use egui_extras::{Column, TableBuilder, TableRow};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, hash::Hash};
 
 enum TimelineRow {
-    Command(NitsSender, NitsCommand),
-    Blank(u32),
+    // ティックの番号 (nits_ticks 内の位置) と送信元、コマンド、
+    // 同じ送信元の同じコマンドタイプの前回のコマンド
+    Command(usize, NitsSender, NitsCommand, Option<NitsCommand>),
+    // まとめた空白ティックの数と最初のティックの番号
+    Blank(u32, usize),
+    Empty(usize),
     Separator,
 }
 
 impl TimelineRow {
     fn get_height(&self) -> f32 {
         match self {
-            TimelineRow::Command(_, _) => 20.0,
-            TimelineRow::Blank(_) => 20.0,
+            TimelineRow::Command(..) => 20.0,
+            TimelineRow::Blank(_, _) => 20.0,
+            TimelineRow::Empty(_) => 20.0,
             TimelineRow::Separator => 4.0,
         }
     }
@@ -95,19 +103,168 @@ impl<T: Ord + std::fmt::Display> FilterUiMap<T> {
         }
     }
 
-    fn add_checkboxes(&mut self, ui: &mut Ui, all_label: &str) {
+    fn add_checkboxes(&mut self, ui: &mut Ui, all_label: &str, label: impl Fn(&T) -> String) {
         self.add_all_checkbox(ui, all_label);
-        for (key, mut value) in self.map.iter_mut() {
-            ui.checkbox(&mut value, key.to_string());
+        for (key, value) in self.map.iter_mut() {
+            ui.checkbox(value, label(key));
         }
     }
 }
 
+// ペイロードの値で行を絞り込む条件
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+enum PayloadFilter {
+    #[default]
+    Any,
+    BitSet(u8),
+    BitClear(u8),
+    // payload & mask == value
+    Masked {
+        mask: u32,
+        value: u32,
+    },
+}
+
+impl PayloadFilter {
+    fn matches(&self, payload: u32) -> bool {
+        match *self {
+            PayloadFilter::Any => true,
+            PayloadFilter::BitSet(bit) => payload >> bit & 1 != 0,
+            PayloadFilter::BitClear(bit) => payload >> bit & 1 == 0,
+            PayloadFilter::Masked { mask, value } => payload & mask == value & mask,
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            PayloadFilter::Any => "Any payload",
+            PayloadFilter::BitSet(_) => "Bit set",
+            PayloadFilter::BitClear(_) => "Bit clear",
+            PayloadFilter::Masked { .. } => "Masked equal",
+        }
+    }
+
+    fn ui(&mut self, ui: &mut Ui, id: Id, payload_bits: u32) {
+        let bit = match *self {
+            PayloadFilter::BitSet(bit) | PayloadFilter::BitClear(bit) => bit,
+            _ => 0,
+        };
+        egui::ComboBox::from_id_salt(id.with("payload_filter"))
+            .selected_text(self.label())
+            .show_ui(ui, |ui| {
+                for filter in [
+                    PayloadFilter::Any,
+                    PayloadFilter::BitSet(bit),
+                    PayloadFilter::BitClear(bit),
+                    PayloadFilter::Masked {
+                        mask: (1 << payload_bits) - 1,
+                        value: 0,
+                    },
+                ] {
+                    let selected = std::mem::discriminant(self) == std::mem::discriminant(&filter);
+                    if ui.selectable_label(selected, filter.label()).clicked() && !selected {
+                        *self = filter;
+                    }
+                }
+            });
+        let digits = payload_bits.div_ceil(4) as usize;
+        match self {
+            PayloadFilter::Any => {}
+            PayloadFilter::BitSet(bit) | PayloadFilter::BitClear(bit) => {
+                ui.add(
+                    egui::DragValue::new(bit)
+                        .range(0..=payload_bits - 1)
+                        .prefix("bit "),
+                );
+            }
+            PayloadFilter::Masked { mask, value } => {
+                ui.label("mask");
+                ui.add(
+                    egui::DragValue::new(mask)
+                        .range(0..=(1u32 << payload_bits) - 1)
+                        .hexadecimal(digits, false, false),
+                );
+                ui.label("value");
+                ui.add(
+                    egui::DragValue::new(value)
+                        .range(0..=(1u32 << payload_bits) - 1)
+                        .hexadecimal(digits, false, false),
+                );
+            }
+        }
+    }
+}
+
+// scroll_to_row がなければ縦のスクロール位置を scroll_offset にする
+fn timeline_table(
+    ui: &mut Ui,
+    id: Id,
+    stick_to_bottom: bool,
+    scroll_to_row: Option<(usize, egui::Align)>,
+    scroll_offset: f32,
+) -> TableBuilder<'_> {
+    let table = TableBuilder::new(ui)
+        .id_salt(id)
+        .cell_layout(Layout::left_to_right(egui::Align::Center))
+        .stick_to_bottom(stick_to_bottom);
+    match scroll_to_row {
+        Some((row, align)) => table.scroll_to_row(row, Some(align)),
+        None => table.vertical_scroll_offset(scroll_offset),
+    }
+}
+
+// ペイロードの定義があれば分解した値を行のツールチップに出す
+fn payload_hover_text(row: &TableRow<'_, '_>, command: &NitsCommand, values: &Values) {
+    if let Some(schema) = values.payload_schema(command.command_type()) {
+        let text = schema
+            .decode(command)
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        row.response().on_hover_text(text);
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NitsTimelineWindow {
     id: Id,
     sender_filter: FilterUiMap<NitsSender>,
     command_type_filter: FilterUiMap<NitsCommandType>,
+    // この長さ以上続く空白ティックを1行にまとめる
+    #[serde(default)]
+    blank_threshold: u32,
+    // ティックの時刻の列を出す (グラフの x 軸と同じく最新のサンプルを 0 とした秒)
+    #[serde(default)]
+    show_time: bool,
+    #[serde(default)]
+    payload_filter: PayloadFilter,
+    // 検索するコマンドタイプとペイロードの条件
+    #[serde(default)]
+    search_command_type: Option<NitsCommandType>,
+    #[serde(default)]
+    search_payload: PayloadFilter,
+    // 最後に見つかったティック
+    #[serde(skip, default)]
+    search_tick: Option<usize>,
+    // None なら全体の設定に従う
+    #[serde(default)]
+    auto_scroll: Option<bool>,
+    #[serde(skip, default = "super::default_following")]
+    following: bool,
+    // "Plot this command" で作成したグラフ
+    #[serde(default)]
+    plot_graph: Option<Id>,
+    #[serde(skip, default)]
+    plot_request: Option<String>,
+    // グラフで印を付けた時刻に最も近いティック
+    #[serde(skip, default)]
+    marked_tick: Option<usize>,
+    #[serde(skip, default)]
+    scroll_to_mark: bool,
+    // 固定した列とビットの列で揃える縦のスクロール位置
+    #[serde(skip, default)]
+    scroll_offset: f32,
 }
 
 impl NitsTimelineWindow {
@@ -116,12 +273,57 @@ impl NitsTimelineWindow {
             id: Id::new(id),
             sender_filter: FilterUiMap::new(),
             command_type_filter: FilterUiMap::new(),
+            blank_threshold: 1,
+            show_time: false,
+            payload_filter: PayloadFilter::Any,
+            search_command_type: None,
+            search_payload: PayloadFilter::Any,
+            search_tick: None,
+            auto_scroll: None,
+            following: true,
+            plot_graph: None,
+            plot_request: None,
+            marked_tick: None,
+            scroll_to_mark: false,
+            scroll_offset: 0.0,
+        }
+    }
+
+    // 印が変わったときはその行までスクロールする
+    pub fn set_marked_tick(&mut self, tick: Option<usize>) {
+        if self.marked_tick != tick {
+            self.marked_tick = tick;
+            self.scroll_to_mark = tick.is_some();
         }
     }
 
+    pub fn plot_graph(&self) -> Option<Id> {
+        self.plot_graph
+    }
+
+    pub fn set_plot_graph(&mut self, id: Id) {
+        self.plot_graph = Some(id);
+    }
+
+    // グラフに追加するよう要求されたチャンネルのキー
+    pub fn take_plot_request(&mut self) -> Option<String> {
+        self.plot_request.take()
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn title(&self) -> &str {
+        "NITS Timeline"
+    }
+
     pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
-        egui::Window::new("NITS Timeline")
-            .id(self.id)
+        super::window(ctx, self.title(), self.id)
             .default_size(vec2(100.0, 200.0))
             .vscroll(true)
             .open(open)
@@ -129,107 +331,415 @@ impl NitsTimelineWindow {
     }
     pub fn ui(&mut self, ui: &mut Ui, values: &Values) {
         let timeline_rows = self.get_timeline_rows(values);
+        let payload_bits = values.nits_payload_bits();
+
+        let mut jump_to_now = false;
+        ui.horizontal(|ui| {
+            ui.label("Collapse blank runs of");
+            ui.add(egui::DragValue::new(&mut self.blank_threshold).range(1..=3600));
+            ui.label("ticks or more");
+            ui.checkbox(&mut self.show_time, "Time");
+            super::auto_scroll_ui(ui, self.id, &mut self.auto_scroll);
+            jump_to_now = !self.following && ui.button("Jump to now").clicked();
+        });
+        ui.horizontal(|ui| {
+            ui.label("Payload");
+            self.payload_filter.ui(ui, self.id, payload_bits);
+        });
+        let search_row = ui
+            .horizontal(|ui| self.search_ui(ui, values, &timeline_rows))
+            .inner;
+        let mut plot_request = None;
 
         ui.style_mut().spacing.item_spacing = vec2(0.0, 2.0);
-        TableBuilder::new(ui)
-            .cell_layout(Layout::left_to_right(egui::Align::Center))
-            .column(Column::auto().at_least(100.0))
-            .column(Column::auto().at_least(30.0))
-            .columns(Column::exact(20.0), 24)
-            .stick_to_bottom(true)
-            .header(20.0, |mut header| {
-                header.col(|ui| {
-                    ui.style_mut().spacing.item_spacing = vec2(4.0, 0.0);
-                    ui.strong("Sender");
-
-                    ui.menu_button("⏷", |ui| {
-                        for sender in values
-                            .get_nits_senders()
-                            .iter()
-                            .map(|s| NitsSender::Command(*s))
-                            .chain([NitsSender::CommonLine])
-                        {
-                            self.sender_filter.set_default(sender, true);
+        let scroll_to_row = if jump_to_now {
+            Some((timeline_rows.len().saturating_sub(1), egui::Align::BOTTOM))
+        } else if let Some(row) = search_row {
+            Some((row, egui::Align::Center))
+        } else if self.scroll_to_mark {
+            self.scroll_to_mark = false;
+            let marked = self.marked_tick.unwrap_or_default();
+            timeline_rows
+                .iter()
+                .position(|r| match r {
+                    TimelineRow::Command(tick, ..) | TimelineRow::Empty(tick) => *tick >= marked,
+                    TimelineRow::Blank(count, start) => start + *count as usize > marked,
+                    TimelineRow::Separator => false,
+                })
+                .map(|row| (row, egui::Align::Center))
+        } else {
+            None
+        };
+        let stick_to_bottom = super::auto_scroll(self.auto_scroll, values);
+        // 2つの表の縦のスクロール位置は前のフレームでスクロールされた方に揃える
+        let scroll_offset = self.scroll_offset;
+        let now = self.show_time.then(|| values.latest_time().unwrap_or(0.0));
+
+        // Tick・送信元・コマンドタイプの列は左に固定し、ビットの列だけを横にスクロールする
+        let (fixed_output, bits_output) = ui
+            .horizontal_top(|ui| {
+                let fixed_output = timeline_table(
+                    ui,
+                    self.id.with(("fixed", self.show_time)),
+                    stick_to_bottom,
+                    scroll_to_row,
+                    scroll_offset,
+                )
+                .column(Column::auto().at_least(40.0))
+                .columns(Column::auto().at_least(50.0), self.show_time as usize)
+                .column(Column::auto().at_least(100.0))
+                .column(Column::auto().at_least(30.0))
+                .scroll_bar_visibility(ScrollBarVisibility::AlwaysHidden)
+                .header(20.0, |header| self.fixed_header(header, values))
+                .body(|body| {
+                    body.heterogeneous_rows(timeline_rows.iter().map(|r| r.get_height()), |row| {
+                        let timeline_row = &timeline_rows[row.index()];
+                        if let Some(key) = self.fixed_row(row, timeline_row, values, now) {
+                            plot_request = Some(key);
                         }
-                        self.sender_filter.add_checkboxes(ui, "All");
                     });
                 });
-
-                header.col(|ui| {
-                    if values.get_nits_command_types().len() > 0 {
-                        ui.menu_button("⏷", |ui| {
-                            for command_type in values.get_nits_command_types() {
-                                self.command_type_filter.set_default(*command_type, true);
+                let bits_output = ScrollArea::horizontal()
+                    .id_salt(self.id.with("bits_scroll"))
+                    .show(ui, |ui| {
+                        // 列の数が変わったらリサイズの状態を作り直す
+                        timeline_table(
+                            ui,
+                            self.id.with(payload_bits),
+                            stick_to_bottom,
+                            scroll_to_row,
+                            scroll_offset,
+                        )
+                        .columns(Column::exact(20.0), payload_bits as usize)
+                        .header(20.0, |mut header| {
+                            for i in (0..payload_bits).rev() {
+                                header.col(|ui| {
+                                    ui.centered_and_justified(|ui| {
+                                        ui.strong(RichText::new(i.to_string()).size(10.0));
+                                    });
+                                });
                             }
-                            self.command_type_filter.add_checkboxes(ui, "All");
-                        });
+                        })
+                        .body(|body| {
+                            body.heterogeneous_rows(
+                                timeline_rows.iter().map(|r| r.get_height()),
+                                |row| {
+                                    let timeline_row = &timeline_rows[row.index()];
+                                    self.bits_row(row, timeline_row, payload_bits, values);
+                                },
+                            );
+                        })
+                    })
+                    .inner;
+                (fixed_output, bits_output)
+            })
+            .inner;
+        let fixed_offset = fixed_output.state.offset.y;
+        let bits_offset = bits_output.state.offset.y;
+        self.scroll_offset = if bits_offset != self.scroll_offset {
+            bits_offset
+        } else {
+            fixed_offset
+        };
+        if fixed_offset != bits_offset {
+            ui.ctx().request_repaint();
+        }
+        self.following = super::is_scrolled_to_bottom(&bits_output);
+        if plot_request.is_some() {
+            self.plot_request = plot_request;
+        }
+    }
+
+    fn fixed_header(&mut self, mut header: TableRow<'_, '_>, values: &Values) {
+        header.col(|ui| {
+            ui.strong("Tick");
+        });
+        if self.show_time {
+            header.col(|ui| {
+                ui.strong("Time");
+            });
+        }
+        header.col(|ui| {
+            ui.style_mut().spacing.item_spacing = vec2(4.0, 0.0);
+            ui.strong("Sender");
+
+            ui.menu_button("⏷", |ui| {
+                for sender in values
+                    .get_nits_senders()
+                    .iter()
+                    .map(|s| NitsSender::Command(*s))
+                    .chain([NitsSender::CommonLine])
+                {
+                    self.sender_filter.set_default(sender, true);
+                }
+                self.sender_filter
+                    .add_checkboxes(ui, "All", ToString::to_string);
+            });
+        });
+
+        header.col(|ui| {
+            if !values.get_nits_command_types().is_empty() {
+                ui.menu_button("⏷", |ui| {
+                    for command_type in values.get_nits_command_types() {
+                        self.command_type_filter.set_default(*command_type, true);
                     }
+                    self.command_type_filter
+                        .add_checkboxes(ui, "All", |c| values.command_type_label(*c));
                 });
+            }
+        });
+    }
 
-                for i in 0..24 {
-                    header.col(|ui| {
-                        ui.centered_and_justified(|ui| {
-                            ui.strong(RichText::new((23 - i).to_string()).size(10.0));
-                        });
-                    });
+    // 固定する列 (Tick・時刻・送信元・コマンドタイプ) を描く
+    // now は show_time のときの時刻の原点
+    // "Plot this command" が選ばれたらグラフに追加するチャンネルのキーを返す
+    fn fixed_row(
+        &self,
+        mut row: TableRow<'_, '_>,
+        timeline_row: &TimelineRow,
+        values: &Values,
+        now: Option<f64>,
+    ) -> Option<String> {
+        let time = |tick: usize| Some(values.nits_tick_time(tick)? - now?);
+        match timeline_row {
+            TimelineRow::Command(tick, sender, value, _) => {
+                row.set_selected(self.is_selected_tick(*tick));
+                if self.command_row(row, *tick, time(*tick), sender, value, values) {
+                    return Some(nits_channel_key(sender, value.command_type()));
                 }
+            }
+            TimelineRow::Blank(blank_count, start) => {
+                let end = start + *blank_count as usize;
+                let times = time(*start).zip(time(end.saturating_sub(1)));
+                self.blank_row(row, *blank_count, *start, times);
+            }
+            TimelineRow::Empty(tick) => {
+                row.set_selected(self.marked_tick == Some(*tick));
+                self.empty_row(row, *tick, time(*tick));
+            }
+            TimelineRow::Separator => {
+                self.separator_row(row, 3 + self.show_time as u32);
+            }
+        }
+        None
+    }
+
+    // 横にスクロールするビットの列を描く
+    fn bits_row(
+        &self,
+        mut row: TableRow<'_, '_>,
+        timeline_row: &TimelineRow,
+        payload_bits: u32,
+        values: &Values,
+    ) {
+        match timeline_row {
+            TimelineRow::Command(tick, _, value, previous) => {
+                row.set_selected(self.is_selected_tick(*tick));
+                self.payload_row(row, value, *previous, values);
+            }
+            TimelineRow::Empty(tick) => {
+                row.set_selected(self.marked_tick == Some(*tick));
+            }
+            TimelineRow::Blank(..) => {}
+            TimelineRow::Separator => {
+                self.separator_row(row, payload_bits);
+            }
+        }
+    }
+
+    fn is_selected_tick(&self, tick: usize) -> bool {
+        self.search_tick == Some(tick) || self.marked_tick == Some(tick)
+    }
+
+    fn search_matches(&self, command: &NitsCommand) -> bool {
+        self.search_command_type
+            .is_none_or(|c| c == command.command_type())
+            && self.search_payload.matches(command.payload())
+    }
+
+    // 検索条件に合う次 (forward でなければ前) のティックを探し、その行を返す
+    // 端まで行ったら反対側から探し直す
+    fn find(&mut self, timeline_rows: &[TimelineRow], forward: bool) -> Option<usize> {
+        let matches: Vec<(usize, usize)> = timeline_rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| match r {
+                TimelineRow::Command(tick, _, command, _) if self.search_matches(command) => {
+                    Some((i, *tick))
+                }
+                _ => None,
             })
-            .body(|body| {
-                body.heterogeneous_rows(timeline_rows.iter().map(|r| r.get_height()), |row| {
-                    let index = row.index();
-                    let timeline_row = &timeline_rows[index];
-
-                    match timeline_row {
-                        TimelineRow::Command(sender, value) => {
-                            self.command_row(row, &sender.to_string(), value);
-                        }
-                        TimelineRow::Blank(blank_count) => {
-                            self.blank_row(row, *blank_count);
-                        }
-                        TimelineRow::Separator => {
-                            self.separator_row(row);
-                        }
-                    }
-                });
+            .collect();
+        let current = self.search_tick;
+        let mut ticks = matches.iter().map(|(_, t)| *t);
+        let tick = if forward {
+            ticks
+                .find(|t| current.is_none_or(|c| *t > c))
+                .or(matches.first().map(|(_, t)| *t))
+        } else {
+            ticks
+                .rev()
+                .find(|t| current.is_none_or(|c| *t < c))
+                .or(matches.last().map(|(_, t)| *t))
+        };
+        self.search_tick = tick;
+        let tick = tick?;
+        matches.iter().find(|(_, t)| *t == tick).map(|(i, _)| *i)
+    }
+
+    // 検索欄。移動先の行を返す
+    fn search_ui(
+        &mut self,
+        ui: &mut Ui,
+        values: &Values,
+        timeline_rows: &[TimelineRow],
+    ) -> Option<usize> {
+        ui.label("Find");
+        egui::ComboBox::from_id_salt(self.id.with("search_command_type"))
+            .selected_text(match self.search_command_type {
+                Some(c) => values.command_type_label(c),
+                None => "Any type".to_owned(),
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.search_command_type, None, "Any type");
+                for c in values.get_nits_command_types() {
+                    ui.selectable_value(
+                        &mut self.search_command_type,
+                        Some(*c),
+                        values.command_type_label(*c),
+                    );
+                }
             });
+        self.search_payload
+            .ui(ui, self.id.with("search"), values.nits_payload_bits());
+        let enabled =
+            self.search_command_type.is_some() || self.search_payload != PayloadFilter::Any;
+        let mut row = None;
+        if ui.add_enabled(enabled, egui::Button::new("◀")).clicked() {
+            row = self.find(timeline_rows, false);
+        }
+        if ui.add_enabled(enabled, egui::Button::new("▶")).clicked() {
+            row = self.find(timeline_rows, true);
+        }
+        if let Some(tick) = self.search_tick {
+            ui.label(format!("tick {}", tick));
+        }
+        row
     }
 
-    fn separator_row(&self, mut row: TableRow<'_, '_>) {
-        for _ in 0..26 {
+    fn separator_row(&self, mut row: TableRow<'_, '_>, columns: u32) {
+        for _ in 0..columns {
             row.col(|ui| {
                 ui.add(egui::Separator::default().horizontal());
             });
         }
     }
 
-    fn blank_row(&self, mut row: TableRow<'_, '_>, blank_count: u32) {
+    // show_time のときの時刻の列
+    fn time_col(&self, row: &mut TableRow<'_, '_>, time: Option<f64>) {
+        if self.show_time {
+            row.col(|ui| {
+                if let Some(time) = time {
+                    ui.label(RichText::new(format!("{:.1}", time)).weak());
+                }
+            });
+        }
+    }
+
+    // times は最初と最後の空白ティックの時刻
+    fn blank_row(
+        &self,
+        mut row: TableRow<'_, '_>,
+        blank_count: u32,
+        start: usize,
+        times: Option<(f64, f64)>,
+    ) {
+        row.col(|_| {});
+        self.time_col(&mut row, times.map(|(start, _)| start));
+        row.col(|ui| {
+            let mut text = format!(
+                "{} Blank Ticks [t={}..{}]",
+                blank_count,
+                start,
+                start + blank_count as usize
+            );
+            if let Some((start, end)) = times {
+                text.push_str(&format!(" [{:.1}..{:.1}]", start, end));
+            }
+            ui.label(RichText::new(text).weak());
+        });
+    }
+
+    fn empty_row(&self, mut row: TableRow<'_, '_>, tick: usize, time: Option<f64>) {
+        row.col(|ui| {
+            ui.label(RichText::new(tick.to_string()).weak());
+        });
+        self.time_col(&mut row, time);
         row.col(|ui| {
-            ui.label(RichText::new(format!("{} ticks", blank_count)).weak());
+            ui.label(RichText::new("(no commands)").weak());
         });
     }
 
-    fn command_row(&self, mut row: TableRow<'_, '_>, sender_label: &str, command: &NitsCommand) {
+    // "Plot this command" が選ばれたら true を返す
+    fn command_row(
+        &self,
+        mut row: TableRow<'_, '_>,
+        tick: usize,
+        time: Option<f64>,
+        sender: &NitsSender,
+        command: &NitsCommand,
+        values: &Values,
+    ) -> bool {
+        let mut plot = false;
         row.col(|ui| {
-            ui.label(sender_label);
+            ui.label(RichText::new(tick.to_string()).weak());
         });
+        self.time_col(&mut row, time);
         row.col(|ui| {
-            ui.label(command.command_type().to_string());
+            ui.add(Label::new(sender.to_string()).sense(Sense::click()))
+                .on_hover_text("Right click to plot this command")
+                .context_menu(|ui| {
+                    if ui.button("Plot this command").clicked() {
+                        plot = true;
+                        ui.close_menu();
+                    }
+                });
+        });
+        row.col(|ui| {
+            ui.label(values.command_type_label(command.command_type()));
         });
-        for i in (0..24).rev() {
+        payload_hover_text(&row, command, values);
+        plot
+    }
+
+    fn payload_row(
+        &self,
+        mut row: TableRow<'_, '_>,
+        command: &NitsCommand,
+        previous: Option<NitsCommand>,
+        values: &Values,
+    ) {
+        for i in (0..values.nits_payload_bits()).rev() {
             row.col(|ui| {
                 let bit = command.payload() >> i & 1;
-                if bit != 0 {
-                    ui.painter().rect_filled(
-                        ui.available_rect_before_wrap(),
-                        1.0,
-                        ui.visuals().gray_out(ui.visuals().weak_text_color()),
-                    );
+                // 前回から変わったビットは 0→1 を緑、1→0 を赤にする
+                let flipped = previous.is_some_and(|p| p.payload() >> i & 1 != bit);
+                let fill = match (flipped, bit != 0) {
+                    (true, true) => Some(Color32::DARK_GREEN),
+                    (true, false) => Some(Color32::DARK_RED),
+                    (false, true) => Some(ui.visuals().gray_out(ui.visuals().weak_text_color())),
+                    (false, false) => None,
+                };
+                if let Some(fill) = fill {
+                    ui.painter()
+                        .rect_filled(ui.available_rect_before_wrap(), 1.0, fill);
                 }
                 ui.centered_and_justified(|ui| {
                     ui.label(format!("{:01b}", bit));
                 });
             });
         }
+        payload_hover_text(&row, command, values);
     }
 
     fn get_timeline_rows(&self, values: &Values) -> Vec<TimelineRow> {
@@ -238,48 +748,66 @@ impl NitsTimelineWindow {
             .get(&NitsSender::CommonLine)
             .unwrap_or(&true);
 
-        let len = values.get_nits_timeline().len();
+        let len = values.nits_ticks().len();
         let mut timeline_rows: Vec<TimelineRow> = Vec::new();
         let mut blank_count = 0;
-        for (t, nits_tick) in values.get_nits_timeline().iter().enumerate() {
+        // 送信元とコマンドタイプごとの直前のコマンド (絞り込みで隠れた行も含める)
+        let mut previous: BTreeMap<(NitsSender, NitsCommandType), NitsCommand> = BTreeMap::new();
+        for (t, nits_tick) in values.nits_ticks().enumerate() {
             let is_last = t + 1 >= len;
             let mut rows_tmp: Vec<TimelineRow> = Vec::new();
 
             for (c, value) in nits_tick.commands() {
                 let sender = NitsSender::Command(*c);
+                let prev = previous.insert((sender, value.command_type()), *value);
                 let pass_sender_filter = *self.sender_filter.get(&sender).unwrap_or(&true);
                 let pass_command_type_filter = *self
                     .command_type_filter
                     .get(&value.command_type())
                     .unwrap_or(&true);
-                if pass_sender_filter && pass_command_type_filter {
-                    rows_tmp.push(TimelineRow::Command(sender, *value));
+                if pass_sender_filter
+                    && pass_command_type_filter
+                    && self.payload_filter.matches(value.payload())
+                {
+                    rows_tmp.push(TimelineRow::Command(t, sender, *value, prev));
                 }
             }
 
+            let commonline = nits_tick.commonline();
+            let prev = previous.insert(
+                (NitsSender::CommonLine, commonline.command_type()),
+                *commonline,
+            );
             let commonline_pass_command_type_filter = *self
                 .command_type_filter
                 .get(&nits_tick.commonline().command_type())
                 .unwrap_or(&true);
-            if commonline_pass_sender_filter && commonline_pass_command_type_filter {
+            if commonline_pass_sender_filter
+                && commonline_pass_command_type_filter
+                && self
+                    .payload_filter
+                    .matches(nits_tick.commonline().payload())
+            {
                 rows_tmp.push(TimelineRow::Command(
+                    t,
                     NitsSender::CommonLine,
-                    *nits_tick.commonline(),
+                    *commonline,
+                    prev,
                 ));
             }
 
             if blank_count > 0 {
-                if rows_tmp.len() > 0 {
-                    timeline_rows.push(TimelineRow::Blank(blank_count));
-                    timeline_rows.push(TimelineRow::Separator);
+                let start = t - blank_count as usize;
+                if !rows_tmp.is_empty() {
+                    self.push_blank_rows(&mut timeline_rows, blank_count, start, true);
                     blank_count = 0;
                 } else if is_last {
-                    timeline_rows.push(TimelineRow::Blank(blank_count + 1));
+                    self.push_blank_rows(&mut timeline_rows, blank_count + 1, start, false);
                     blank_count = 0;
                 }
             }
 
-            if rows_tmp.len() > 0 {
+            if !rows_tmp.is_empty() {
                 timeline_rows.append(&mut rows_tmp);
                 if !is_last {
                     timeline_rows.push(TimelineRow::Separator);
@@ -289,6 +817,29 @@ impl NitsTimelineWindow {
             }
         }
 
-        return timeline_rows;
+        timeline_rows
+    }
+
+    // 閾値より短い空白は1ティックずつ、長い空白はまとめて表示する
+    fn push_blank_rows(
+        &self,
+        timeline_rows: &mut Vec<TimelineRow>,
+        blank_count: u32,
+        start: usize,
+        separator: bool,
+    ) {
+        if blank_count < self.blank_threshold {
+            for i in 0..blank_count {
+                timeline_rows.push(TimelineRow::Empty(start + i as usize));
+                if separator || i + 1 < blank_count {
+                    timeline_rows.push(TimelineRow::Separator);
+                }
+            }
+        } else {
+            timeline_rows.push(TimelineRow::Blank(blank_count, start));
+            if separator {
+                timeline_rows.push(TimelineRow::Separator);
+            }
+        }
     }
 }
@@ -0,0 +1,124 @@
+use crate::values::Values;
+use egui::{pos2, vec2, Context, Id, Rect, Sense, Shape, Ui};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, hash::Hash};
+
+// 1つのスパークラインに描画する点の最大数
+const MAX_SPARKLINE_POINTS: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+pub struct OverviewWindow {
+    id: Id,
+    keys: BTreeSet<String>,
+    #[serde(skip, default)]
+    open_request: Option<String>,
+}
+
+impl OverviewWindow {
+    pub fn new(id: impl Hash) -> Self {
+        Self {
+            id: Id::new(id),
+            keys: BTreeSet::new(),
+            open_request: None,
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn title(&self) -> &str {
+        "Overview"
+    }
+
+    // グラフを開くよう要求されたキー
+    pub fn take_open_request(&mut self) -> Option<String> {
+        self.open_request.take()
+    }
+
+    pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
+        super::window(ctx, self.title(), self.id)
+            .default_size(vec2(500.0, 300.0))
+            .vscroll(true)
+            .open(open)
+            .show(ctx, |ui| self.ui(ui, values));
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, values: &Values) {
+        ui.menu_button("Keys ⏷", |ui| {
+            for key in values.keys() {
+                let mut checked = self.keys.contains(key);
+                if ui.checkbox(&mut checked, key).changed() {
+                    if checked {
+                        self.keys.insert(key.to_owned());
+                    } else {
+                        self.keys.remove(key);
+                    }
+                }
+            }
+        });
+        ui.separator();
+
+        ui.horizontal_wrapped(|ui| {
+            for key in &self.keys {
+                ui.vertical(|ui| {
+                    ui.set_width(120.0);
+                    let text = match values.get_last_value_for_key(key) {
+                        Some(v) => format!("{}: {}", key, values.format_value(key, v)),
+                        None => key.to_owned(),
+                    };
+                    ui.label(text);
+                    let (rect, response) =
+                        ui.allocate_exact_size(vec2(120.0, 40.0), Sense::click());
+                    if let Some(data) = values.values_for_key(key) {
+                        sparkline(ui, rect, data.iter().map(|v| *v as f32));
+                    }
+                    if response.on_hover_text("Click to open graph").clicked() {
+                        self.open_request = Some(key.to_owned());
+                    }
+                });
+            }
+        });
+    }
+}
+
+// 値の推移を rect に収まる折れ線として描画する
+fn sparkline(ui: &Ui, rect: Rect, data: impl ExactSizeIterator<Item = f32>) {
+    let visuals = ui.visuals();
+    ui.painter()
+        .rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+    let len = data.len();
+    let step = len.div_ceil(MAX_SPARKLINE_POINTS).max(1);
+    let points: Vec<(usize, f32)> = data
+        .enumerate()
+        .step_by(step)
+        .filter(|(_, v)| v.is_finite())
+        .collect();
+    if points.len() < 2 {
+        return;
+    }
+    let (min, max) = points
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (_, v)| {
+            (min.min(*v), max.max(*v))
+        });
+    let range = if max > min { max - min } else { 1.0 };
+    let points = points
+        .into_iter()
+        .map(|(i, v)| {
+            pos2(
+                rect.left() + rect.width() * i as f32 / (len - 1).max(1) as f32,
+                rect.bottom() - rect.height() * (v - min) / range,
+            )
+        })
+        .collect();
+    ui.painter().add(Shape::line(
+        points,
+        visuals.widgets.noninteractive.fg_stroke,
+    ));
+}
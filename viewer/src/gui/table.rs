@@ -10,8 +10,16 @@ pub struct TableWindow {
     id: Id,
     title: String,
     keys: Vec<String>,
+    // None なら全体の設定に従う
+    #[serde(default)]
+    auto_scroll: Option<bool>,
+    #[serde(skip, default = "super::default_following")]
+    following: bool,
     #[serde(skip, default)]
     save_dialog: Option<FileDialog>,
+    // App のダイアログに出すファイルのエラー
+    #[serde(skip, default)]
+    file_error: Option<String>,
 }
 
 impl TableWindow {
@@ -20,24 +28,48 @@ impl TableWindow {
             id: Id::new(id),
             title: key.clone(),
             keys: vec![key],
+            auto_scroll: None,
+            following: true,
             save_dialog: None,
+            file_error: None,
         }
     }
 
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn take_file_error(&mut self) -> Option<String> {
+        self.file_error.take()
+    }
+
     pub fn show(&mut self, ctx: &Context, open: &mut bool, values: &Values) {
-        egui::Window::new(&self.title)
-            .id(self.id)
+        super::window(ctx, &self.title, self.id)
             .default_size(vec2(100.0, 200.0))
             .vscroll(true)
             .open(open)
             .show(ctx, |ui| self.ui(ui, values));
     }
     pub fn ui(&mut self, ui: &mut Ui, values: &Values) {
+        let query = super::key_filter_ui(ui, self.id.with("key_filter"));
         ScrollArea::horizontal()
             .id_salt(self.id.with("header"))
             .show(ui, |ui| {
                 ui.horizontal(|ui| {
-                    for key in values.keys() {
+                    // テーブルに追加済みのキーは絞り込んでも残す
+                    let keys: Vec<_> = values
+                        .keys()
+                        .filter(|k| self.keys.contains(*k) || super::key_matches(k, &query))
+                        .collect();
+                    for key in keys {
                         if ui.selectable_label(self.keys.contains(key), key).clicked() {
                             if let Some(index) = self.keys.iter().position(|k| k == key) {
                                 self.keys.remove(index);
@@ -57,52 +89,81 @@ impl TableWindow {
             fd.open();
             self.save_dialog = Some(fd);
         }
+        let jump_to_now = ui
+            .horizontal(|ui| {
+                super::auto_scroll_ui(ui, self.id, &mut self.auto_scroll);
+                !self.following && ui.button("Jump to now").clicked()
+            })
+            .inner;
         ui.separator();
-        let table = TableBuilder::new(ui)
+        let columns: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| values.values_for_key(key))
+            .collect();
+        let rows = values.sample_rows(self.keys.iter().map(String::as_str));
+        let max_len = rows.len();
+        let mut table = TableBuilder::new(ui)
             .cell_layout(Layout::left_to_right(egui::Align::Center))
             .columns(Column::auto(), self.keys.len())
-            .stick_to_bottom(true);
-        table
+            .stick_to_bottom(super::auto_scroll(self.auto_scroll, values));
+        if jump_to_now {
+            table = table.scroll_to_row(max_len.saturating_sub(1), Some(egui::Align::BOTTOM));
+        }
+        // 列の並べ替えと削除は見出しのコンテキストメニューから行う
+        let mut moved = None;
+        let mut removed = None;
+        let output = table
             .header(20.0, |mut header| {
-                for key in &self.keys {
+                for (i, key) in self.keys.iter().enumerate() {
                     header.col(|ui| {
-                        ui.strong(key);
+                        ui.strong(key).context_menu(|ui| {
+                            if i > 0 && ui.button("Move left").clicked() {
+                                moved = Some((i, i - 1));
+                                ui.close_menu();
+                            }
+                            if i + 1 < self.keys.len() && ui.button("Move right").clicked() {
+                                moved = Some((i, i + 1));
+                                ui.close_menu();
+                            }
+                            if ui.button("Remove").clicked() {
+                                removed = Some(i);
+                                ui.close_menu();
+                            }
+                        });
                     });
                 }
             })
             .body(|body| {
-                let mut values: Vec<_> = self
-                    .keys
-                    .iter()
-                    .map(|key| values.values_for_key(key))
-                    .collect();
-                let max_len = values
-                    .iter()
-                    .map(|v| v.as_ref().map(|v| v.len()).unwrap_or_default())
-                    .max()
-                    .unwrap_or_default();
                 body.rows(20.0, max_len, |mut row| {
                     let index = row.index();
-                    for iter in values.iter_mut() {
+                    for (i, (key, column)) in self.keys.iter().zip(columns.iter()).enumerate() {
                         row.col(|ui| {
-                            if let Some(it) = iter.as_mut() {
-                                let offset = max_len - it.len();
-                                if offset <= index {
-                                    if let Some(v) = it.get(index - offset) {
-                                        ui.label(v.to_string());
-                                    } else {
-                                        *iter = None;
-                                    }
-                                }
+                            if let Some(v) = column
+                                .as_ref()
+                                .and_then(|v| v.get(rows.sample_index(index, i)?))
+                            {
+                                ui.label(values.format_value(key, *v));
                             }
                         });
                     }
                 });
             });
+        self.following = super::is_scrolled_to_bottom(&output);
+        if let Some((from, to)) = moved {
+            self.keys.swap(from, to);
+            self.title = self.keys.join(",");
+        }
+        if let Some(index) = removed {
+            self.keys.remove(index);
+            self.title = self.keys.join(",");
+        }
         if let Some(save_dialog) = self.save_dialog.as_mut() {
             if save_dialog.show(ui.ctx()).selected() {
                 if let Some(path) = save_dialog.path() {
-                    let _ = values.save_csv(path, self.keys.iter());
+                    if let Err(e) = values.save_csv(path, self.keys.iter()) {
+                        self.file_error = Some(format!("Failed to save CSV: {}", e));
+                    }
                 }
                 self.save_dialog = None;
             }
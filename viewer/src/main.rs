@@ -1,10 +1,15 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod gui;
+mod demo;
 mod values;
 mod nits;
+mod number_format;
 mod settings;
 mod range_check;
+mod wire_format;
+mod expression;
+mod recorder;
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
@@ -31,11 +36,18 @@ fn main() {
     let web_options = eframe::WebOptions::default();
 
     wasm_bindgen_futures::spawn_local(async {
+        use eframe::wasm_bindgen::JsCast as _;
+        let canvas = eframe::web_sys::window()
+            .and_then(|w| w.document())
+            .and_then(|d| d.get_element_by_id("canvas"))
+            .and_then(|e| e.dyn_into::<eframe::web_sys::HtmlCanvasElement>().ok())
+            .expect("failed to find canvas");
+
         eframe::WebRunner::new()
             .start(
-                "canvas",
+                canvas,
                 web_options,
-                Box::new(|cc| Box::new(app::App::new(cc))),
+                Box::new(|cc| Ok(Box::new(gui::app::App::new(cc)))),
             )
             .await
             .expect("failed to start")
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NumberFormat {
+    pub decimal_separator: char,
+    pub grouping_separator: char,
+    pub digit_grouping: bool,
+    // 小数の表示桁数 (None なら丸めずに全桁表示する)
+    pub decimal_places: Option<usize>,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            grouping_separator: ',',
+            digit_grouping: false,
+            decimal_places: None,
+        }
+    }
+}
+
+impl NumberFormat {
+    // 小数を表示桁数に合わせて整形する
+    pub fn format_float(&self, value: f64) -> String {
+        let formatted = match self.decimal_places {
+            Some(places) => format!("{:.*}", places, value),
+            None => format!("{:?}", value),
+        };
+        self.apply(&formatted)
+    }
+
+    // Rust の標準の表記で整形済みの数値に小数点と桁区切りを適用する
+    pub fn apply(&self, formatted: &str) -> String {
+        let (sign, unsigned) = match formatted.strip_prefix('-') {
+            Some(s) => ("-", s),
+            None => ("", formatted),
+        };
+        let int_len = unsigned
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(unsigned.len());
+        let (int_part, rest) = unsigned.split_at(int_len);
+
+        let mut result = String::with_capacity(formatted.len() + int_len / 3);
+        result.push_str(sign);
+        for (i, c) in int_part.chars().enumerate() {
+            if self.digit_grouping && i > 0 && (int_len - i) % 3 == 0 {
+                result.push(self.grouping_separator);
+            }
+            result.push(c);
+        }
+        match rest.strip_prefix('.') {
+            Some(fract) => {
+                result.push(self.decimal_separator);
+                result.push_str(fract);
+            }
+            None => result.push_str(rest),
+        }
+        result
+    }
+}
@@ -3,7 +3,7 @@ use std::{
     ops::{Bound, RangeBounds},
 };
 
-pub fn range_check<T: PartialOrd>(
+pub fn range_check<T: PartialOrd + Clone>(
     range: &impl RangeBounds<T>,
     value: T,
 ) -> Result<(), OutOfRangeError<T>> {
@@ -12,25 +12,42 @@ pub fn range_check<T: PartialOrd>(
     } else {
         Err(OutOfRangeError {
             value,
-            start: range.start_bound(),
-            end: range.end_bound(),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        })
+    }
+}
+
+// 値を借用して調べる版 (エラーには値と範囲を文字列にして持たせる)
+pub fn range_check_ref<T: PartialOrd + fmt::Display>(
+    range: &impl RangeBounds<T>,
+    value: &T,
+) -> Result<(), OutOfRangeError<String>> {
+    if range.contains(value) {
+        Ok(())
+    } else {
+        Err(OutOfRangeError {
+            value: value.to_string(),
+            start: range.start_bound().map(|v| v.to_string()),
+            end: range.end_bound().map(|v| v.to_string()),
         })
     }
 }
 
 #[derive(Debug)]
-pub struct OutOfRangeError<'a, T> {
+pub struct OutOfRangeError<T> {
     value: T,
-    start: Bound<&'a T>,
-    end: Bound<&'a T>,
+    start: Bound<T>,
+    end: Bound<T>,
 }
 
-impl<T: std::fmt::Display> fmt::Display for OutOfRangeError<'_, T> {
+impl<T: std::fmt::Display> fmt::Display for OutOfRangeError<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Value {} is out of range: expected", self.value)?;
-        match self.start {
+        write!(f, "Value {} is out of range: expected ", self.value)?;
+        match &self.start {
+            // 下限を含まない場合は "0<..=10" のように表す
             Bound::Excluded(v) => {
-                write!(f, "{}..", v)?;
+                write!(f, "{}<..", v)?;
             }
             Bound::Included(v) => {
                 write!(f, "{}..", v)?;
@@ -39,7 +56,7 @@ impl<T: std::fmt::Display> fmt::Display for OutOfRangeError<'_, T> {
                 write!(f, "..")?;
             }
         }
-        match self.end {
+        match &self.end {
             Bound::Excluded(v) => {
                 write!(f, "{}", v)?;
             }
@@ -52,4 +69,37 @@ impl<T: std::fmt::Display> fmt::Display for OutOfRangeError<'_, T> {
     }
 }
 
-impl<T: fmt::Display + fmt::Debug> std::error::Error for OutOfRangeError<'_, T> {}
+impl<T: fmt::Display + fmt::Debug> std::error::Error for OutOfRangeError<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(start: Bound<i32>, end: Bound<i32>, value: i32) -> String {
+        range_check(&(start, end), value).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn display_shows_each_kind_of_bound() {
+        assert_eq!(
+            message(Bound::Excluded(0), Bound::Included(10), 0),
+            "Value 0 is out of range: expected 0<..=10"
+        );
+        assert_eq!(
+            message(Bound::Included(0), Bound::Excluded(10), 10),
+            "Value 10 is out of range: expected 0..10"
+        );
+        assert_eq!(
+            message(Bound::Excluded(0), Bound::Excluded(10), 10),
+            "Value 10 is out of range: expected 0<..10"
+        );
+        assert_eq!(
+            message(Bound::Unbounded, Bound::Included(10), 11),
+            "Value 11 is out of range: expected ..=10"
+        );
+        assert_eq!(
+            message(Bound::Included(0), Bound::Unbounded, -1),
+            "Value -1 is out of range: expected 0.."
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::settings::RecordRotation;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+// 受信したデータを1行1メッセージの JSON として書き出し続ける
+// RecordRotation の大きさか長さを超えたら番号を付けた次のファイルに切り替える
+pub struct Recorder {
+    path: PathBuf,
+    // 今書いているファイルの番号 (0 は指定されたファイル名のまま)
+    index: u32,
+    writer: BufWriter<File>,
+    // 今のファイルに書いたバイト数とティック数、最初に書いた時刻
+    bytes: u64,
+    ticks: u64,
+    started: Option<f64>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            index: 0,
+            writer: BufWriter::new(File::create(path)?),
+            bytes: 0,
+            ticks: 0,
+            started: None,
+        })
+    }
+
+    // "recording.jsonl" の index 番目は "recording-001.jsonl" のようにする
+    fn file_path(&self, index: u32) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}-{:03}.{}", stem, index, ext.to_string_lossy()),
+            None => format!("{}-{:03}", stem, index),
+        };
+        self.path.with_file_name(name)
+    }
+
+    pub fn current_path(&self) -> PathBuf {
+        self.file_path(self.index)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    fn should_rotate(&self, rotation: &RecordRotation, time: f64) -> bool {
+        // 空のファイルのまま次に進まないようにする
+        if self.bytes == 0 {
+            return false;
+        }
+        let too_large = rotation
+            .max_mib
+            .is_some_and(|mib| self.bytes >= mib as u64 * 1024 * 1024);
+        let too_long = rotation
+            .max_minutes
+            .zip(self.started)
+            .is_some_and(|(minutes, started)| time - started >= minutes as f64 * 60.0);
+        too_large || too_long
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let path = self.file_path(self.index + 1);
+        self.writer = BufWriter::new(File::create(path)?);
+        self.index += 1;
+        self.bytes = 0;
+        self.ticks = 0;
+        self.started = None;
+        Ok(())
+    }
+
+    // ticks はこの行に含まれるティック数
+    pub fn write(
+        &mut self,
+        line: &impl Serialize,
+        ticks: usize,
+        time: f64,
+        rotation: &RecordRotation,
+    ) -> std::io::Result<()> {
+        if self.should_rotate(rotation, time) {
+            self.rotate()?;
+        }
+        let mut bytes = serde_json::to_vec(line)?;
+        bytes.push(b'\n');
+        self.writer.write_all(&bytes)?;
+        self.bytes += bytes.len() as u64;
+        self.ticks += ticks as u64;
+        self.started.get_or_insert(time);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テストごとに空のディレクトリを作る
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sw_logger_recorder_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn line_count(path: &Path) -> usize {
+        std::fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = temp_dir("size");
+        let path = dir.join("recording.jsonl");
+        let rotation = RecordRotation {
+            max_mib: Some(1),
+            max_minutes: None,
+        };
+        let mut recorder = Recorder::create(&path).unwrap();
+        // 1 行あたり 1KiB 強なので 1MiB を超えたところで切り替わる
+        let line = "x".repeat(1024);
+        for i in 0..1500 {
+            recorder.write(&line, 1, i as f64, &rotation).unwrap();
+        }
+        assert_eq!(recorder.current_path(), dir.join("recording-001.jsonl"));
+        recorder.finish().unwrap();
+
+        let first = line_count(&path);
+        assert!(first * 1027 >= 1024 * 1024 && (first - 1) * 1027 < 1024 * 1024);
+        assert_eq!(first + line_count(&dir.join("recording-001.jsonl")), 1500);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_duration() {
+        let dir = temp_dir("duration");
+        let path = dir.join("recording.jsonl");
+        let rotation = RecordRotation {
+            max_mib: None,
+            max_minutes: Some(1),
+        };
+        let mut recorder = Recorder::create(&path).unwrap();
+        // 10 秒ごとに 2 ティックずつ、150 秒分書く
+        for i in 0..15 {
+            recorder.write(&i, 2, i as f64 * 10.0, &rotation).unwrap();
+        }
+        assert_eq!(recorder.ticks(), 6);
+        recorder.finish().unwrap();
+
+        assert_eq!(line_count(&path), 6);
+        assert_eq!(line_count(&dir.join("recording-001.jsonl")), 6);
+        assert_eq!(line_count(&dir.join("recording-002.jsonl")), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
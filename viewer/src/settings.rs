@@ -1,22 +1,242 @@
+use crate::{
+    nits::{NitsCommandType, PayloadSchema, DEFAULT_PAYLOAD_BITS},
+    number_format::NumberFormat,
+    wire_format::WireFormat,
+};
+use egui::{Color32, ThemePreference};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// 長さの異なるチャンネルを行として並べるときの揃え方
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum SampleAlignment {
+    // 最新の値を揃える
+    #[default]
+    End,
+    // 最も古い値を揃える
+    Start,
+    // 受け取った時刻が同じサンプルを同じ行に置く (Values::sample_rows)
+    Timestamp,
+}
+
+impl SampleAlignment {
+    // max_len 行のうち index 行目に置かれる、長さ len のチャンネルのサンプル位置
+    // 行の位置だけで対応させるときは Timestamp も End と同じにする
+    pub fn sample_index(&self, index: usize, len: usize, max_len: usize) -> Option<usize> {
+        match self {
+            Self::End | Self::Timestamp => index.checked_sub(max_len - len),
+            Self::Start => Some(index),
+        }
+        .filter(|i| *i < len)
+    }
+}
+
+// CSV の区切り文字と小数点
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CsvFormat {
+    // None なら読み込み時はヘッダーから推測し、保存時は小数点と被らない方を使う
+    pub delimiter: Option<char>,
+    pub decimal_separator: char,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: None,
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl CsvFormat {
+    // ヘッダー行に最も多く含まれる区切り文字の候補
+    pub fn load_delimiter(&self, header: &str) -> char {
+        self.delimiter.unwrap_or_else(|| {
+            [',', ';', '\t']
+                .into_iter()
+                .rev()
+                .max_by_key(|c| header.matches(*c).count())
+                .unwrap()
+        })
+    }
+
+    pub fn save_delimiter(&self) -> char {
+        self.delimiter.unwrap_or(if self.decimal_separator == ',' {
+            ';'
+        } else {
+            ','
+        })
+    }
+
+    pub fn parse(&self, cell: &str) -> Result<f64, std::num::ParseFloatError> {
+        if self.decimal_separator == '.' {
+            cell.parse()
+        } else {
+            cell.replace(self.decimal_separator, ".").parse()
+        }
+    }
+
+    pub fn format(&self, value: f64) -> String {
+        let formatted = value.to_string();
+        if self.decimal_separator == '.' {
+            formatted
+        } else {
+            formatted.replace('.', &self.decimal_separator.to_string())
+        }
+    }
+}
+
+// 線ごとに色を指定していないグラフの線に使う色
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct LineColors {
+    // 最初の線の色 (cycle が空のときだけ使う)
+    pub accent: Option<Color32>,
+    // 空でなければ自動の色の代わりにこの順で繰り返し使う
+    pub cycle: Vec<Color32>,
+}
+
+impl LineColors {
+    pub fn color(&self, index: usize) -> Color32 {
+        if !self.cycle.is_empty() {
+            return self.cycle[index % self.cycle.len()];
+        }
+        match self.accent {
+            Some(accent) if index == 0 => accent,
+            // egui_plot の自動の色と同じ並び
+            _ => {
+                let golden_ratio = (5.0_f32.sqrt() - 1.0) / 2.0;
+                egui::ecolor::Hsva::new(index as f32 * golden_ratio, 0.85, 0.5, 1.0).into()
+            }
+        }
+    }
+}
+
+// 記録ファイルを次のファイルに切り替える大きさと長さ (None なら切り替えない)
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordRotation {
+    pub max_mib: Option<u32>,
+    pub max_minutes: Option<u32>,
+}
+
+// 保持期間 (retention_period) はサンプル数で持ち、sample_rate_hz で秒に換算する
+// 任意入力できる保持期間の上限 (2時間)
+pub const MAX_RETENTION_SECONDS: u32 = 60 * 60 * 2;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub retention_period: u32,
+    // 1秒あたりのサンプル数 (バッチ内のサンプルの時刻やグラフの期間の換算に使う)
+    pub sample_rate_hz: u32,
     pub keep_values: bool,
+    pub auto_connect: bool,
+    // 保持期間を変更するときに確認を出すメモリ使用量の目安 (MiB)
+    pub memory_budget: u32,
+    // 受け付けるキーの数の上限 (キー名が毎回変わるような送信で際限なく増えないように)
+    pub max_keys: usize,
+    pub force_float_keys: BTreeSet<String>,
+    pub number_format: NumberFormat,
+    // 値が変化しないチャンネルをメインテーブルの Constants にまとめる
+    pub collapse_constants: bool,
+    // メインテーブルでキーをこの文字より前の部分ごとにまとめる (None なら並べるだけ)
+    pub key_group_separator: Option<char>,
+    // 「データ無し」を表す値 (全チャンネル共通とチャンネル別)
+    pub missing_value: Option<f64>,
+    pub missing_values: BTreeMap<String, f64>,
+    // 全チャンネル共通の「データ無し」の値を使わないキー
+    pub missing_value_exempt: BTreeSet<String>,
+    // キーごとの単位 (メインテーブルの値とグラフの Y 軸に付ける)
+    pub units: BTreeMap<String, String>,
+    pub sample_alignment: SampleAlignment,
+    // テーブルを最新の行に追従させる (ウィンドウごとに上書きできる)
+    pub auto_scroll: bool,
+    // CSV 読み込み時に数値として読めないセルを 0 にする
+    pub csv_zero_fill: bool,
+    pub csv_format: CsvFormat,
+    // この秒数データが届かなければ受信状態を赤く表示する
+    pub stale_timeout: f64,
+    // 1ティックごとに 1 ずつ増えるキー (値が飛んだところを抜けとして記録する)
+    pub tick_counter_key: Option<String>,
+    pub wire_format: WireFormat,
+    // JsonText の入れ子のオブジェクトを "a.b" のキーに展開する
+    pub flatten_nested: bool,
+    // 受け取ったキーから表示するキーへの置き換え
+    pub key_renames: BTreeMap<String, String>,
+    // NITS のコマンドのうちペイロードとする下位のビット数 (残りの上位がコマンドタイプ)
+    pub nits_payload_bits: u32,
+    // NITS のコマンドタイプに付ける名前
+    pub command_type_names: BTreeMap<u8, String>,
+    // NITS Timeline でペイロードを分解して表示するための定義
+    pub payload_schemas: BTreeMap<NitsCommandType, PayloadSchema>,
+    // 他のキーから計算するキーの名前と式
+    pub computed_keys: BTreeMap<String, String>,
+    pub theme: ThemePreference,
+    pub record_rotation: RecordRotation,
+    pub line_colors: LineColors,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             retention_period: 3600,
+            sample_rate_hz: 60,
             keep_values: false,
+            auto_connect: false,
+            memory_budget: 512,
+            max_keys: 4096,
+            force_float_keys: BTreeSet::new(),
+            number_format: NumberFormat::default(),
+            collapse_constants: false,
+            key_group_separator: None,
+            missing_value: None,
+            missing_values: BTreeMap::new(),
+            missing_value_exempt: BTreeSet::new(),
+            units: BTreeMap::new(),
+            sample_alignment: SampleAlignment::End,
+            auto_scroll: true,
+            csv_zero_fill: false,
+            csv_format: CsvFormat::default(),
+            stale_timeout: 5.0,
+            tick_counter_key: None,
+            wire_format: WireFormat::JsonText,
+            flatten_nested: false,
+            key_renames: BTreeMap::new(),
+            nits_payload_bits: DEFAULT_PAYLOAD_BITS,
+            command_type_names: BTreeMap::new(),
+            payload_schemas: BTreeMap::new(),
+            computed_keys: BTreeMap::new(),
+            theme: ThemePreference::System,
+            record_rotation: RecordRotation::default(),
+            line_colors: LineColors::default(),
         }
     }
 }
 
 impl Settings {
     pub fn max_len(&self) -> usize {
-        self.retention_period.try_into().unwrap()
+        self.retention_period
+            .min(MAX_RETENTION_SECONDS * self.sample_rate_hz)
+            .try_into()
+            .unwrap()
+    }
+
+    // 1サンプルの長さ (秒)
+    pub fn sample_seconds(&self) -> f64 {
+        1.0 / self.sample_rate_hz.max(1) as f64
+    }
+
+    pub fn is_missing_value(&self, key: &str, value: f64) -> bool {
+        match self.missing_values.get(key) {
+            Some(m) => *m == value,
+            None => {
+                !self.missing_value_exempt.contains(key)
+                    && self.missing_value.is_some_and(|m| m == value)
+            }
+        }
+    }
+
+    pub fn memory_budget_bytes(&self) -> usize {
+        self.memory_budget as usize * 1024 * 1024
     }
 }
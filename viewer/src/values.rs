@@ -1,9 +1,15 @@
 use crate::{
-    nits::{NitsCommand, NitsCommandType, NitsRelativeCarCount, NitsTick},
-    settings::Settings,
+    expression::Expr,
+    nits::{
+        is_nits_raw_channel, nits_channel_key, NitsCommand, NitsCommandType, NitsRelativeCarCount,
+        NitsSender, NitsTick, PayloadSchema,
+    },
+    number_format::NumberFormat,
+    settings::{LineColors, SampleAlignment, Settings},
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
     cell::RefCell,
     collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     fs::File,
@@ -30,14 +36,18 @@ impl<T> QueueMaxLen<T> {
         }
     }
 
-    /*fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.vec.len()
-    }*/
+    }
 
     fn iter(&self) -> std::collections::vec_deque::Iter<'_, T> {
         self.vec.iter()
     }
 
+    fn iter_mut(&mut self) -> std::collections::vec_deque::IterMut<'_, T> {
+        self.vec.iter_mut()
+    }
+
     fn vec(&self) -> &VecDeque<T> {
         &self.vec
     }
@@ -49,6 +59,8 @@ impl<T> QueueMaxLen<T> {
         } else if len > max_len {
             self.vec.drain(0..(len - max_len));
         }
+        // 短くしたときは余った領域を解放する
+        self.vec.shrink_to(max_len);
         self.max_len = max_len;
     }
 
@@ -71,16 +83,66 @@ impl<T> QueueMaxLen<T> {
     fn back(&self) -> Option<&T> {
         self.vec.back()
     }
+
+    fn clear(&mut self) {
+        self.vec.clear();
+    }
+}
+
+// tick カウンタが飛んだ箇所 (time は飛んだ後の最初のサンプルの時刻)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gap {
+    pub time: f64,
+    pub dropped: u64,
+}
+
+// 保持している値の統計 (NaN と無限大は除く)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    // 標本標準偏差 (サンプルが1つなら 0)
+    pub stddev: f64,
 }
 
+// 直近この数のサンプルが全て整数なら整数チャンネルとみなす
+const INTEGRAL_WINDOW: usize = 60;
+
 #[derive(Debug, Deserialize)]
 pub struct Values {
-    values: BTreeMap<String, QueueMaxLen<f32>>,
+    values: BTreeMap<String, QueueMaxLen<f64>>,
+    // values と同じ並びで、各サンプルを受け取った時刻 (秒)
+    #[serde(default)]
+    times: BTreeMap<String, QueueMaxLen<f64>>,
+    // add_data に渡された時刻を保存している時刻に換算するための差
+    #[serde(skip)]
+    time_offset: Option<f64>,
     #[serde(skip)]
     settings: Rc<RefCell<Settings>>,
+    #[serde(skip)]
+    integral_counts: BTreeMap<String, usize>,
+    #[serde(skip)]
+    constant_counts: BTreeMap<String, usize>,
     nits_timeline: QueueMaxLen<NitsTick>,
     nits_senders: BTreeSet<NitsRelativeCarCount>,
     nits_command_types: BTreeSet<NitsCommandType>,
+    #[serde(default)]
+    nits_channels: BTreeSet<String>,
+    // Settings::computed_keys を解析した式
+    #[serde(skip)]
+    computed: BTreeMap<String, Expr>,
+    // 最後に受け取った tick カウンタの値と、保持しているサンプルの範囲の抜け
+    #[serde(skip)]
+    last_tick: Option<f64>,
+    #[serde(skip)]
+    gaps: VecDeque<Gap>,
+    // キーの数の上限を超えた新しいキーを捨てたバッチの数
+    #[serde(skip)]
+    rejected_count: usize,
+    // times の中で最も新しい時刻 (push で更新する)
+    #[serde(skip)]
+    latest_time: Option<f64>,
 }
 
 impl Serialize for Values {
@@ -90,49 +152,228 @@ impl Serialize for Values {
     {
         #[derive(Serialize)]
         struct V {
-            values: BTreeMap<String, QueueMaxLen<f32>>,
+            values: BTreeMap<String, QueueMaxLen<f64>>,
+            times: BTreeMap<String, QueueMaxLen<f64>>,
             nits_timeline: QueueMaxLen<NitsTick>,
             nits_senders: BTreeSet<NitsRelativeCarCount>,
             nits_command_types: BTreeSet<NitsCommandType>,
+            nits_channels: BTreeSet<String>,
         }
 
         if self.settings.borrow().keep_values {
             V {
                 values: self.values.clone(),
+                times: self.times.clone(),
                 nits_timeline: self.nits_timeline.clone(),
                 nits_senders: self.nits_senders.clone(),
                 nits_command_types: self.nits_command_types.clone(),
+                nits_channels: self.nits_channels.clone(),
             }
         } else {
             V {
                 values: self
                     .values
-                    .iter()
-                    .map(|(k, _)| (k.clone(), QueueMaxLen::new()))
+                    .keys()
+                    .map(|k| (k.clone(), QueueMaxLen::new()))
                     .collect(),
+                times: BTreeMap::new(),
                 nits_timeline: QueueMaxLen::new(),
                 nits_senders: BTreeSet::new(),
                 nits_command_types: BTreeSet::new(),
+                nits_channels: BTreeSet::new(),
             }
         }
         .serialize(serializer)
     }
 }
 
+// NITS のティックから作られるチャンネル ("NITS 1 Front 0x02" など)
+fn is_nits_derived_channel(key: &str) -> bool {
+    key.starts_with("NITS ") && !is_nits_raw_channel(key)
+}
+
+// ティックに現れた派生チャンネルのキーとペイロード
+fn nits_channel_values(nits_tick: &NitsTick) -> BTreeMap<String, f64> {
+    nits_tick
+        .commands()
+        .iter()
+        .map(|(sender, command)| (NitsSender::Command(*sender), command))
+        .chain([(NitsSender::CommonLine, nits_tick.commonline())])
+        .map(|(sender, command)| {
+            (
+                nits_channel_key(&sender, command.command_type()),
+                command.payload() as f64,
+            )
+        })
+        .collect()
+}
+
 impl Values {
     pub fn new(settings: Rc<RefCell<Settings>>) -> Self {
         let max_len = settings.borrow().max_len();
-        Self {
+        let mut values = Self {
             values: BTreeMap::new(),
+            times: BTreeMap::new(),
+            time_offset: None,
             settings,
+            integral_counts: BTreeMap::new(),
+            constant_counts: BTreeMap::new(),
             nits_timeline: QueueMaxLen::with_capacity(max_len),
             nits_senders: BTreeSet::new(),
             nits_command_types: BTreeSet::new(),
-        }
+            nits_channels: BTreeSet::new(),
+            computed: BTreeMap::new(),
+            last_tick: None,
+            gaps: VecDeque::new(),
+            rejected_count: 0,
+            latest_time: None,
+        };
+        values.update_computed_keys();
+        values
     }
 
+    // 復元した直後にも呼ばれるので、保存されていない状態をここで作り直す
     pub fn set_settings(&mut self, settings: Rc<RefCell<Settings>>) {
         self.settings = settings;
+        self.update_computed_keys();
+        // 復元したティックはビット列しか持たないので、設定の分割で読み直す
+        let payload_bits = self.nits_payload_bits();
+        for nits_tick in self.nits_timeline.iter_mut() {
+            nits_tick.set_payload_bits(payload_bits);
+        }
+        self.latest_time = self
+            .times
+            .values()
+            .filter_map(|t| t.back().copied())
+            .max_by(f64::total_cmp);
+    }
+
+    // Settings::computed_keys の変更を反映する (解析できない式は無視する)
+    pub fn update_computed_keys(&mut self) {
+        self.computed = self
+            .settings
+            .borrow()
+            .computed_keys
+            .iter()
+            .filter_map(|(key, text)| Some((key.clone(), Expr::parse(text).ok()?)))
+            .collect();
+    }
+
+    // 式に出てくる受信したキーのキューと、その最大の長さ
+    fn computed_sources<'a>(
+        &'a self,
+        expr: &'a Expr,
+    ) -> (Vec<(&'a str, &'a VecDeque<f64>)>, usize) {
+        let sources: Vec<_> = expr
+            .keys()
+            .into_iter()
+            .filter_map(|k| Some((k, self.values.get(k)?.vec())))
+            .collect();
+        let max_len = sources.iter().map(|(_, q)| q.len()).max().unwrap_or(0);
+        (sources, max_len)
+    }
+
+    // 計算キーの index 番目のサンプル
+    // 各キーのサンプルは sample_alignment に合わせて対応させ、無いものは NaN とする
+    fn evaluate_at(
+        &self,
+        expr: &Expr,
+        sources: &[(&str, &VecDeque<f64>)],
+        max_len: usize,
+        index: usize,
+    ) -> f64 {
+        let alignment = self.sample_alignment();
+        expr.eval(&|key| {
+            sources
+                .iter()
+                .find(|(k, _)| *k == key)
+                .and_then(|(_, q)| q.get(alignment.sample_index(index, q.len(), max_len)?))
+                .copied()
+                .unwrap_or(f64::NAN)
+        })
+    }
+
+    // 受信したキーと同じ名前の計算キーは受信したキーを優先する
+    fn computed_expr(&self, key: &str) -> Option<&Expr> {
+        if self.values.contains_key(key) {
+            None
+        } else {
+            self.computed.get(key)
+        }
+    }
+
+    // 受信した値だけを消す (チャンネルのキーは残すのでウィンドウの設定はそのまま使える)
+    pub fn clear(&mut self) {
+        for v in self.values.values_mut() {
+            v.clear();
+        }
+        for t in self.times.values_mut() {
+            t.clear();
+        }
+        self.time_offset = None;
+        self.integral_counts.clear();
+        self.constant_counts.clear();
+        self.nits_timeline.clear();
+        self.nits_senders.clear();
+        self.nits_command_types.clear();
+        self.rejected_count = 0;
+        self.latest_time = None;
+        self.clear_gaps();
+    }
+
+    pub fn clear_gaps(&mut self) {
+        self.last_tick = None;
+        self.gaps.clear();
+    }
+
+    // tick カウンタのキーの値が 1 ずつ増えていないところを抜けとして記録する
+    fn detect_gaps<S: std::hash::BuildHasher>(
+        &mut self,
+        data: &HashMap<String, Vec<f64>, S>,
+        now: f64,
+        sample_seconds: f64,
+    ) {
+        let Some(key) = self.settings.borrow().tick_counter_key.clone() else {
+            return;
+        };
+        let Some(ticks) = data.get(&key) else {
+            return;
+        };
+        let len = ticks.len();
+        for (i, tick) in ticks.iter().enumerate() {
+            if !tick.is_finite() {
+                continue;
+            }
+            // 値が戻ったときはカウンタがリセットされたとみなして抜けにしない
+            if let Some(dropped) = self.last_tick.map(|last| tick - last - 1.0) {
+                if dropped >= 1.0 {
+                    self.gaps.push_back(Gap {
+                        time: now - (len - 1 - i) as f64 * sample_seconds,
+                        dropped: dropped as u64,
+                    });
+                }
+            }
+            self.last_tick = Some(*tick);
+        }
+        // 保持しているサンプルより古い抜けは捨てる
+        let oldest = self
+            .times
+            .values()
+            .filter_map(|t| t.vec().front().copied())
+            .min_by(f64::total_cmp);
+        if let Some(oldest) = oldest {
+            while self.gaps.front().is_some_and(|g| g.time < oldest) {
+                self.gaps.pop_front();
+            }
+        }
+    }
+
+    pub fn gaps(&self) -> &VecDeque<Gap> {
+        &self.gaps
+    }
+
+    pub fn dropped_ticks(&self) -> u64 {
+        self.gaps.iter().map(|g| g.dropped).sum()
     }
 
     pub fn set_max_len(&mut self) {
@@ -141,12 +382,65 @@ impl Values {
         for v in self.values.values_mut() {
             v.set_max_len(max_len);
         }
+        for t in self.times.values_mut() {
+            t.set_max_len(max_len);
+        }
         self.nits_timeline.set_max_len(max_len);
         self.update_nits();
     }
 
-    fn push(&mut self, key: String, values: Vec<f32>) {
+    // 上限を超える新しいキーは記録しない
+    fn accepts_key(&self, key: &str) -> bool {
+        self.values.contains_key(key) || self.values.len() < self.settings.borrow().max_keys
+    }
+
+    // end_time は最後のサンプルの時刻で、それより前のサンプルは1ティックずつ遡る
+    // 新しいキーは先に accepts_key で確かめる
+    fn push(&mut self, key: String, mut values: Vec<f64>, end_time: f64) {
         let max_len = self.settings.borrow().max_len();
+        let sample_seconds = self.settings.borrow().sample_seconds();
+        // 「データ無し」を表す値は NaN に置き換える
+        // NITS のチャンネルはビット列やペイロードなので、どんな値でもそのまま残す
+        if !is_nits_raw_channel(&key) && !is_nits_derived_channel(&key) {
+            let settings = self.settings.borrow();
+            for v in values.iter_mut() {
+                if settings.is_missing_value(&key, *v) {
+                    *v = f64::NAN;
+                }
+            }
+        }
+        // 最後に小数を含む値が来てからのサンプル数を数える
+        let count = self.integral_counts.entry(key.clone()).or_default();
+        for v in &values {
+            if v.is_nan() || v.fract() == 0.0 {
+                *count += 1;
+            } else {
+                *count = 0;
+            }
+        }
+        // 末尾から同じ値が続いているサンプル数を数える
+        let mut last = self.get_last_value_for_key(&key);
+        let count = self.constant_counts.entry(key.clone()).or_default();
+        for v in &values {
+            if last == Some(*v) {
+                *count += 1;
+            } else {
+                *count = 1;
+            }
+            last = Some(*v);
+        }
+        let len = values.len();
+        self.times
+            .entry(key.clone())
+            .or_insert_with(|| QueueMaxLen::with_capacity(max_len))
+            .extend(
+                (0..len)
+                    .map(|i| end_time - (len - 1 - i) as f64 * sample_seconds)
+                    .collect(),
+            );
+        if len > 0 {
+            self.latest_time = Some(self.latest_time.map_or(end_time, |t| t.max(end_time)));
+        }
         let v = self
             .values
             .entry(key)
@@ -154,20 +448,43 @@ impl Values {
         v.extend(values);
     }
 
-    pub fn add_data<S: std::hash::BuildHasher>(&mut self, data: HashMap<String, Vec<f32>, S>) {
+    // time はバッチを受け取った時刻 (秒)
+    // None の場合は前回のデータの続きとして1ティックずつ進める
+    pub fn add_data<S: std::hash::BuildHasher>(
+        &mut self,
+        data: HashMap<String, Vec<f64>, S>,
+        time: Option<f64>,
+    ) {
+        let latest_time = self.latest_time();
+        let sample_seconds = self.settings.borrow().sample_seconds();
+        let batch_len = data.values().map(|v| v.len()).max().unwrap_or(0);
+        let now = match time {
+            Some(time) => {
+                // 復元したデータや前回のバッチより前にならないように換算する
+                let offset = *self
+                    .time_offset
+                    .get_or_insert(latest_time.map_or(0.0, |t| t + sample_seconds - time));
+                time + offset
+            }
+            None => latest_time.unwrap_or(0.0) + batch_len as f64 * sample_seconds,
+        };
+
+        self.detect_gaps(&data, now, sample_seconds);
+
         // NITS N01 から NITS N31 までの値を取得
         let mut nits_data: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
         for i in 0..=31 {
-            if let Some(channel) = data.get(&String::from(format!("NITS N{:02}", i))) {
-                nits_data.insert(i, channel.iter().map(|v| v.to_bits()).collect());
+            if let Some(channel) = data.get(&format!("NITS N{:02}", i)) {
+                nits_data.insert(i, channel.iter().map(|v| (*v as f32).to_bits()).collect());
             }
         }
 
         // NITS N32 (コモンライン) を取得し、他のチャンネルの値と時系列的に紐づける
-        if let Some(n32) = data.get(&String::from("NITS N32")) {
+        if let Some(n32) = data.get("NITS N32") {
             let len = n32.len();
+            let payload_bits = self.nits_payload_bits();
             for (i, commonline_f) in n32.iter().enumerate() {
-                let commonline = NitsCommand::new(commonline_f.to_bits());
+                let commonline = NitsCommand::new((*commonline_f as f32).to_bits(), payload_bits);
                 self.nits_command_types.insert(commonline.command_type());
                 let car_count_front = commonline.payload() & 15;
                 let car_count_back = commonline.payload() >> 5 & 15;
@@ -176,14 +493,14 @@ impl Values {
 
                 for j in -(car_count_front as i32)..=(car_count_back as i32) {
                     let key = NitsRelativeCarCount::new(j);
-                    let channel_number = key.get_channel_number(
-                        car_count_front.try_into().unwrap(),
-                        car_count_back.try_into().unwrap(),
-                    );
+                    let channel_number = key.get_channel_number(car_count_front, car_count_back);
                     if let Ok(ch) = channel_number {
                         if let Some(channel) = nits_data.get(&ch) {
-                            if let Some(c) = channel.get((i + channel.len()).saturating_sub(len)) {
-                                let command = NitsCommand::new(*c);
+                            // 各チャンネルは末尾 (最新) を N32 と揃える
+                            // N32 より短いチャンネルは、古い方の tick に対応するサンプルが無いものとする
+                            let index = (i + channel.len()).checked_sub(len);
+                            if let Some(c) = index.and_then(|j| channel.get(j)) {
+                                let command = NitsCommand::new(*c, payload_bits);
                                 self.nits_senders.insert(key);
                                 self.nits_command_types.insert(command.command_type());
                                 nits_tick.add_command(key, command);
@@ -192,16 +509,86 @@ impl Values {
                     }
                 }
 
+                self.push_nits_channels(&nits_tick, now - (len - 1 - i) as f64 * sample_seconds);
                 self.nits_timeline.push(nits_tick);
             }
         }
 
         // NITSに限らない通常のデータの処理
+        let mut dropped = None;
         for (k, v) in data {
-            self.push(k, v);
+            if self.accepts_key(&k) {
+                self.push(k, v, now);
+            } else {
+                dropped.get_or_insert(k);
+            }
+        }
+        // 新しいキーを捨てたバッチを数える (ログは最初の1回だけ出す)
+        if let Some(key) = dropped {
+            if self.rejected_count == 0 {
+                log::warn!(
+                    "key limit ({}) reached, dropping new key \"{}\" and any further new keys",
+                    self.settings.borrow().max_keys,
+                    key
+                );
+            }
+            self.rejected_count += 1;
+        }
+    }
+
+    // 記録するように選ばれた派生チャンネルに、送信元・コマンド種別ごとのペイロードを記録する
+    // そのティックに現れなかったチャンネルは直前の値を保持する
+    fn push_nits_channels(&mut self, nits_tick: &NitsTick, time: f64) {
+        if self.nits_channels.is_empty() {
+            return;
+        }
+        let tick_values = nits_channel_values(nits_tick);
+        let samples: Vec<(String, f64)> = self
+            .nits_channels
+            .iter()
+            .filter_map(|k| {
+                let v = tick_values
+                    .get(k)
+                    .copied()
+                    .or_else(|| self.get_last_value_for_key(k))?;
+                Some((k.clone(), v))
+            })
+            .collect();
+        for (key, value) in samples {
+            self.push(key, vec![value], time);
         }
     }
 
+    // 派生チャンネルを記録するようにし、保持しているティックからそれまでの値を作る
+    // キーの数の上限で記録できなければ false を返す
+    pub fn enable_nits_channel(&mut self, key: &str) -> bool {
+        if self.nits_channels.contains(key) {
+            return true;
+        }
+        let mut last = None;
+        let samples: Vec<(f64, f64)> = self
+            .nits_timeline
+            .iter()
+            .enumerate()
+            .filter_map(|(i, nits_tick)| {
+                if let Some(v) = nits_channel_values(nits_tick).get(key) {
+                    last = Some(*v);
+                }
+                Some((self.nits_tick_time(i)?, last?))
+            })
+            .collect();
+        if !self.accepts_key(key) {
+            return false;
+        }
+        let end_time = samples.last().map_or(0.0, |(time, _)| *time);
+        self.push(key.to_owned(), vec![], end_time);
+        for (time, value) in samples {
+            self.push(key.to_owned(), vec![value], time);
+        }
+        self.nits_channels.insert(key.to_owned());
+        true
+    }
+
     fn update_nits(&mut self) {
         // nits_senders と nits_command_types をリセット
         self.nits_senders = BTreeSet::new();
@@ -216,29 +603,260 @@ impl Values {
         }
     }
 
+    // 各キューが max_len まで埋まったときのおおよそのメモリ使用量
+    pub fn approx_memory_bytes(&self, max_len: usize) -> usize {
+        let values = self.values.len() * max_len * (std::mem::size_of::<f64>() * 2);
+        let nits_tick = std::mem::size_of::<NitsTick>()
+            + self.nits_senders.len() * std::mem::size_of::<(NitsRelativeCarCount, NitsCommand)>();
+        values + max_len * nits_tick
+    }
+
+    // 今確保しているおおよそのメモリ量 (キューの容量から数える)
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let queues: usize = self
+            .values
+            .iter()
+            .chain(self.times.iter())
+            .map(|(k, q)| k.capacity() + q.vec().capacity() * std::mem::size_of::<f64>())
+            .sum();
+        let nits = self.nits_timeline.vec().capacity() * std::mem::size_of::<NitsTick>()
+            + self.nits_timeline.len()
+                * self.nits_senders.len()
+                * std::mem::size_of::<(NitsRelativeCarCount, NitsCommand)>();
+        queues + nits
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn rejected_count(&self) -> usize {
+        self.rejected_count
+    }
+
+    // 全チャンネルで保持しているサンプル数の合計
+    pub fn sample_count(&self) -> usize {
+        self.values.values().map(|v| v.len()).sum()
+    }
+
     pub fn contains_key(&self, key: &str) -> bool {
-        self.values.contains_key(key)
+        self.values.contains_key(key) || self.computed.contains_key(key)
     }
 
+    // 受信したキーをキー順に返し、その後に計算キーを返す
     pub fn keys(&self) -> impl Iterator<Item = &String> {
-        self.values.keys()
+        self.values.keys().chain(
+            self.computed
+                .keys()
+                .filter(|k| !self.values.contains_key(k.as_str())),
+        )
     }
 
-    pub fn iter_for_key(
+    // 各サンプルの時刻と値
+    // 時刻の無い古い保存データは最初の時刻から1ティックずつ遡った時刻とする
+    pub fn iter_with_time_for_key(
         &self,
         key: &str,
-    ) -> Option<impl Iterator<Item = &f32> + ExactSizeIterator + DoubleEndedIterator> {
-        self.values.get(key).map(|v| v.iter())
+    ) -> Option<impl Iterator<Item = (f64, f64)> + '_> {
+        let values = self.values_for_key(key)?;
+        // 計算キーは式に出てくるキーのうち最も長いものの時刻を使う
+        let time_key = match self.computed_expr(key) {
+            Some(expr) => {
+                let (sources, _) = self.computed_sources(expr);
+                sources
+                    .into_iter()
+                    .max_by_key(|(_, q)| q.len())
+                    .map_or(key, |(k, _)| k)
+            }
+            None => key,
+        };
+        let times = self.times.get(time_key).map(|t| t.vec());
+        let time_len = times.map_or(0, |t| t.len());
+        let missing = values.len().saturating_sub(time_len);
+        let first = times
+            .and_then(|t| t.front().copied())
+            .or(self.latest_time())
+            .unwrap_or(0.0);
+        let sample_seconds = self.settings.borrow().sample_seconds();
+        let synthesized = (0..missing).map(move |i| first - (missing - i) as f64 * sample_seconds);
+        let times = synthesized.chain(
+            times
+                .into_iter()
+                .flatten()
+                .skip(time_len.saturating_sub(values.len()))
+                .copied(),
+        );
+        Some(times.zip((0..values.len()).map(move |i| values[i])))
+    }
+
+    // 最後に受け取ったサンプルの時刻
+    pub fn latest_time(&self) -> Option<f64> {
+        self.latest_time
+    }
+
+    // 計算キーは呼ばれるたびに全サンプルを計算する
+    pub fn values_for_key(&self, key: &str) -> Option<Cow<'_, VecDeque<f64>>> {
+        if let Some(q) = self.values.get(key) {
+            return Some(Cow::Borrowed(q.vec()));
+        }
+        let expr = self.computed.get(key)?;
+        let (sources, max_len) = self.computed_sources(expr);
+        Some(Cow::Owned(
+            (0..max_len)
+                .map(|index| self.evaluate_at(expr, &sources, max_len, index))
+                .collect(),
+        ))
+    }
+
+    pub fn is_integral(&self, key: &str) -> bool {
+        if self.settings.borrow().force_float_keys.contains(key) {
+            return false;
+        }
+        let len = self.values.get(key).map(|v| v.len()).unwrap_or(0);
+        let count = self.integral_counts.get(key).copied().unwrap_or(0);
+        len > 0 && count >= len.min(INTEGRAL_WINDOW)
+    }
+
+    pub fn stats_for_key(&self, key: &str) -> Option<KeyStats> {
+        let values = self.values_for_key(key)?;
+        let (count, sum, min, max) = values.iter().filter(|v| v.is_finite()).fold(
+            (0, 0.0, f64::INFINITY, f64::NEG_INFINITY),
+            |(count, sum, min, max), v| (count + 1, sum + v, min.min(*v), max.max(*v)),
+        );
+        if count == 0 {
+            return None;
+        }
+        let mean = sum / count as f64;
+        let variance = if count > 1 {
+            values
+                .iter()
+                .filter(|v| v.is_finite())
+                .map(|v| (v - mean).powi(2))
+                .sum::<f64>()
+                / (count - 1) as f64
+        } else {
+            0.0
+        };
+        Some(KeyStats {
+            min,
+            max,
+            mean,
+            stddev: variance.sqrt(),
+        })
+    }
+
+    // 保持している全てのサンプルが同じ値かどうか
+    pub fn is_constant(&self, key: &str) -> bool {
+        let len = self.values.get(key).map(|v| v.len()).unwrap_or(0);
+        let count = self.constant_counts.get(key).copied().unwrap_or(0);
+        len > 1 && count >= len
+    }
+
+    pub fn format_value(&self, key: &str, value: f64) -> String {
+        let number_format = &self.settings.borrow().number_format;
+        if self.is_integral(key) {
+            number_format.apply(&format!("{:.0}", value))
+        } else {
+            number_format.format_float(value)
+        }
+    }
+
+    pub fn unit(&self, key: &str) -> Option<String> {
+        self.settings.borrow().units.get(key).cloned()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.settings.borrow().sample_rate_hz
+    }
+
+    pub fn line_colors(&self) -> LineColors {
+        self.settings.borrow().line_colors.clone()
+    }
+
+    pub fn number_format(&self) -> NumberFormat {
+        self.settings.borrow().number_format.clone()
+    }
+
+    pub fn auto_scroll(&self) -> bool {
+        self.settings.borrow().auto_scroll
     }
 
-    pub fn values_for_key(&self, key: &str) -> Option<&VecDeque<f32>> {
-        match self.values.get(key) {
-            Some(q) => Some(q.vec()),
-            None => None,
+    // 名前が設定されていれば "0x02 Brake" のように付け加える
+    pub fn command_type_label(&self, command_type: NitsCommandType) -> String {
+        match self
+            .settings
+            .borrow()
+            .command_type_names
+            .get(&command_type.bits())
+        {
+            Some(name) => format!("{} {}", command_type, name),
+            None => command_type.to_string(),
         }
     }
 
-    pub fn get_last_value_for_key(&self, key: &str) -> Option<f32> {
+    pub fn payload_schema(&self, command_type: NitsCommandType) -> Option<PayloadSchema> {
+        self.settings
+            .borrow()
+            .payload_schemas
+            .get(&command_type)
+            .cloned()
+    }
+
+    pub fn sample_alignment(&self) -> SampleAlignment {
+        self.settings.borrow().sample_alignment
+    }
+
+    // keys のチャンネルを sample_alignment に従って行に並べる
+    pub fn sample_rows<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> SampleRows {
+        let keys: Vec<&str> = keys.into_iter().collect();
+        let alignment = self.sample_alignment();
+        if alignment != SampleAlignment::Timestamp {
+            let lens: Vec<usize> = keys
+                .iter()
+                .map(|k| self.values_for_key(k).map_or(0, |v| v.len()))
+                .collect();
+            let max_len = lens.iter().copied().max().unwrap_or(0);
+            return SampleRows::Index {
+                alignment,
+                lens,
+                max_len,
+            };
+        }
+        // (時刻, 列, サンプル位置) を時刻順に並べ、同じ時刻のサンプルを1行にまとめる
+        let mut samples: Vec<(f64, usize, usize)> = keys
+            .iter()
+            .enumerate()
+            .flat_map(|(column, key)| {
+                self.iter_with_time_for_key(key)
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                    .map(move |(i, (t, _))| (t, column, i))
+            })
+            .collect();
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        let mut rows: Vec<Vec<Option<usize>>> = vec![];
+        let mut row_time = None;
+        for (time, column, index) in samples {
+            let same_row =
+                row_time == Some(time) && rows.last().is_some_and(|r| r[column].is_none());
+            if !same_row {
+                rows.push(vec![None; keys.len()]);
+                row_time = Some(time);
+            }
+            if let Some(row) = rows.last_mut() {
+                row[column] = Some(index);
+            }
+        }
+        SampleRows::Timestamp(rows)
+    }
+
+    pub fn get_last_value_for_key(&self, key: &str) -> Option<f64> {
+        if let Some(expr) = self.computed_expr(key) {
+            let (sources, max_len) = self.computed_sources(expr);
+            let index = max_len.checked_sub(1)?;
+            return Some(self.evaluate_at(expr, &sources, max_len, index));
+        }
         self.values
             .get(key)
             .as_ref()
@@ -246,8 +864,51 @@ impl Values {
             .cloned()
     }
 
+    // time に最も近いティックの nits_ticks 内の位置
+    pub fn nits_tick_at(&self, time: f64) -> Option<usize> {
+        // ティックは NITS N32 のサンプルと末尾を揃えて並んでいる
+        let times = self.times.get("NITS N32")?.vec();
+        let i = times.partition_point(|t| *t < time);
+        let i = if i > 0 && (i == times.len() || time - times[i - 1] < times[i] - time) {
+            i - 1
+        } else {
+            i
+        };
+        if i >= times.len() {
+            return None;
+        }
+        (i + self.nits_timeline.len()).checked_sub(times.len())
+    }
+
+    // nits_ticks 内の位置のティックの時刻 (nits_tick_at の逆)
+    pub fn nits_tick_time(&self, tick: usize) -> Option<f64> {
+        let times = self.times.get("NITS N32")?.vec();
+        let index = (tick + times.len()).checked_sub(self.nits_timeline.len())?;
+        times.get(index).copied()
+    }
+
+    pub fn nits_payload_bits(&self) -> u32 {
+        self.settings.borrow().nits_payload_bits
+    }
+
+    // NITS のコマンドの分割を変え、保持しているティックも新しい分割で読み直す
+    // 派生チャンネルはキーのコマンドタイプが変わるので選び直す (それまでの値は残す)
+    pub fn set_nits_payload_bits(&mut self, payload_bits: u32) {
+        self.settings.borrow_mut().nits_payload_bits = payload_bits;
+        for nits_tick in self.nits_timeline.iter_mut() {
+            nits_tick.set_payload_bits(payload_bits);
+        }
+        self.nits_channels.clear();
+        self.update_nits();
+    }
+
     pub fn get_nits_timeline(&self) -> &VecDeque<NitsTick> {
-        &self.nits_timeline.vec()
+        self.nits_timeline.vec()
+    }
+
+    // 保持している NITS のティックを古い順に返す
+    pub fn nits_ticks(&self) -> impl ExactSizeIterator<Item = &NitsTick> + DoubleEndedIterator {
+        self.get_nits_timeline().iter()
     }
 
     pub fn get_nits_senders(&self) -> &BTreeSet<NitsRelativeCarCount> {
@@ -258,26 +919,71 @@ impl Values {
         &self.nits_command_types
     }
 
-    pub fn load_csv<P: AsRef<Path>>(&mut self, file_path: P) {
-        if let Ok(file) = File::open(file_path) {
-            let mut first_row: Option<Vec<String>> = None;
+    // NITS N00〜N32 は add_data を通して NITS Timeline に戻る
+    // 数値として読めないセルは zero_fill なら 0 として、そうでなければ無かったものとして扱う
+    // 空のセルは常に無かったものとして扱う
+    // 最後まで読み込んだうえで、最初に見つかった読めないセルをエラーとして返す
+    pub fn load_csv<P: AsRef<Path>>(
+        &mut self,
+        file_path: P,
+        zero_fill: bool,
+    ) -> Result<(), CsvError> {
+        let file = File::open(file_path).map_err(CsvError::Io)?;
+        let format = self.settings.borrow().csv_format.clone();
+        let mut first_row: Option<Vec<String>> = None;
+        let mut delimiter = ',';
+        // NITS N32 があれば NITS のティックから作られるチャンネルは読まずに作り直す
+        let mut has_nits = false;
+        let mut error = None;
 
-            for result in BufReader::new(file).lines() {
-                if let Ok(l) = result {
-                    let row = l.split(',');
+        for (i, l) in BufReader::new(file).lines().enumerate() {
+            let l = l.map_err(CsvError::Io)?;
+            if first_row.is_none() {
+                delimiter = format.load_delimiter(&l);
+            }
+            let row = l.split(delimiter);
 
-                    if let Some(ref keys) = first_row {
-                        let mut data = HashMap::new();
-                        for (key, v) in keys.iter().zip(row) {
-                            data.insert(key.clone(), vec![v.parse::<f32>().unwrap()]);
+            if let Some(ref keys) = first_row {
+                let mut data = HashMap::new();
+                for (key, v) in keys.iter().zip(row) {
+                    let v = v.trim();
+                    if v.is_empty() || (has_nits && is_nits_derived_channel(key)) {
+                        continue;
+                    }
+                    let v = match format.parse(v) {
+                        Ok(v) => v,
+                        Err(_) => {
+                            error.get_or_insert_with(|| CsvError::Parse {
+                                line: i + 1,
+                                column: key.clone(),
+                                value: v.to_owned(),
+                            });
+                            if !zero_fill {
+                                continue;
+                            }
+                            0.0
                         }
-                        self.add_data(data);
-                    } else {
-                        first_row = Some(row.into_iter().map(|s| String::from(s)).collect());
+                    };
+                    data.insert(key.clone(), vec![v]);
+                }
+                self.add_data(data, None);
+            } else {
+                let keys: Vec<String> = row.map(String::from).collect();
+                has_nits = keys.iter().any(|k| k == "NITS N32");
+                // 派生チャンネルの列は読み込んだティックから作り直す
+                if has_nits {
+                    for key in keys.iter().filter(|k| is_nits_derived_channel(k)) {
+                        self.enable_nits_channel(key);
                     }
                 }
+                first_row = Some(keys);
             }
         }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     pub fn save_csv<'a, K>(&self, path: &Path, keys: K) -> Result<(), std::io::Error>
@@ -285,42 +991,487 @@ impl Values {
         K: Iterator<Item = &'a String>,
     {
         let mut writer = BufWriter::new(File::create(path)?);
-        let mut values = Vec::with_capacity(self.values.len());
-        let mut first = true;
-        let mut max_len = 0;
-        for key in keys {
-            if let Some(v) = self.values_for_key(key) {
-                if first {
-                    first = false
-                } else {
-                    writer.write_all(",".as_bytes())?;
-                }
-                writer.write_all(key.as_bytes())?;
-                max_len = max_len.max(v.len());
-                values.push(v);
-            }
-        }
+        self.write_csv(&mut writer, keys)?;
+        writer.flush()
+    }
+
+    fn write_csv<'a, K>(&self, writer: &mut impl Write, keys: K) -> Result<(), std::io::Error>
+    where
+        K: Iterator<Item = &'a String>,
+    {
+        let format = self.settings.borrow().csv_format.clone();
+        let delimiter = format.save_delimiter().to_string();
+        let columns: Vec<(&String, Cow<'_, VecDeque<f64>>)> = keys
+            .filter_map(|key| Some((key, self.values_for_key(key)?)))
+            .collect();
+        let header: Vec<&str> = columns.iter().map(|(key, _)| key.as_str()).collect();
+        writer.write_all(header.join(&delimiter).as_bytes())?;
         writer.write_all("\n".as_bytes())?;
-        for index in 0..max_len {
-            for (i, vec) in values.iter().enumerate() {
-                let offset = max_len - vec.len();
-                if offset > index {
-                    writer.write_all(",".as_bytes())?;
-                    continue;
-                }
-                if let Some(v) = vec.get(index - offset) {
-                    if i == 0 {
-                        writer.write_fmt(format_args!("{}", v))?;
-                    } else {
-                        writer.write_fmt(format_args!(",{}", v))?;
-                    }
-                } else {
-                    writer.write_all(",".as_bytes())?;
-                }
-            }
+        let rows = self.sample_rows(header);
+        // 短い列は空欄にして、どの行も列数 - 1 個の区切りになるようにする
+        for index in 0..rows.len() {
+            let row: Vec<String> = columns
+                .iter()
+                .enumerate()
+                .map(|(i, (_, vec))| {
+                    rows.sample_index(index, i)
+                        .and_then(|j| vec.get(j))
+                        .map(|v| format.format(*v))
+                        .unwrap_or_default()
+                })
+                .collect();
+            writer.write_all(row.join(&delimiter).as_bytes())?;
             writer.write_all("\n".as_bytes())?;
         }
-        writer.flush()?;
         Ok(())
     }
 }
+
+// 長さの異なるチャンネルを並べた表の行
+pub enum SampleRows {
+    // 行の位置から SampleAlignment::sample_index でサンプルの位置を求める
+    Index {
+        alignment: SampleAlignment,
+        lens: Vec<usize>,
+        max_len: usize,
+    },
+    // 時刻の早い順の行ごとの、各チャンネルのサンプルの位置
+    Timestamp(Vec<Vec<Option<usize>>>),
+}
+
+impl SampleRows {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Index { max_len, .. } => *max_len,
+            Self::Timestamp(rows) => rows.len(),
+        }
+    }
+
+    // row 行目の column 列目に置くサンプルの位置
+    pub fn sample_index(&self, row: usize, column: usize) -> Option<usize> {
+        match self {
+            Self::Index {
+                alignment,
+                lens,
+                max_len,
+            } => alignment.sample_index(row, lens[column], *max_len),
+            Self::Timestamp(rows) => rows.get(row)?.get(column).copied().flatten(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CsvError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        column: String,
+        value: String,
+    },
+}
+
+impl std::fmt::Display for CsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Parse {
+                line,
+                column,
+                value,
+            } => write!(
+                f,
+                "Line {}, column \"{}\": \"{}\" is not a number",
+                line, column, value
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_values() -> Values {
+        Values::new(Rc::new(RefCell::new(Settings::default())))
+    }
+
+    // ティックを NITS N01〜N32 のチャンネルのバッチにする
+    fn batch(ticks: &[NitsTick]) -> HashMap<String, Vec<f64>> {
+        let mut data: HashMap<String, Vec<f64>> = HashMap::new();
+        for tick in ticks {
+            for (key, value) in tick.to_channels() {
+                data.entry(key).or_default().push(value);
+            }
+        }
+        data
+    }
+
+    // 前方 front 両、後方 back 両の編成のコモンライン
+    fn commonline(front: u32, back: u32) -> NitsCommand {
+        NitsCommand::from_parts(0x01, back << 5 | front)
+    }
+
+    #[test]
+    fn shrinking_max_len_releases_capacity() {
+        let mut queue = QueueMaxLen::with_capacity(0);
+        queue.set_max_len(1800);
+        queue.extend((0..1800).collect());
+        assert_eq!(queue.len(), 1800);
+        assert!(queue.vec().capacity() >= 1800);
+
+        queue.set_max_len(10);
+        assert_eq!(queue.len(), 10);
+        assert_eq!(
+            queue.iter().copied().collect::<Vec<_>>(),
+            (1790..1800).collect::<Vec<_>>()
+        );
+        assert!(queue.vec().capacity() < 1800);
+    }
+
+    #[test]
+    fn add_data_reconstructs_nits_timeline() {
+        let ticks: Vec<NitsTick> = (0..4)
+            .map(|t| {
+                let mut tick = NitsTick::new(commonline(1, 2));
+                for c in -1..=2 {
+                    tick.add_command(
+                        NitsRelativeCarCount::new(c),
+                        NitsCommand::from_parts(0x10 + t as u8, (c + 8) as u32 * 16 + t),
+                    );
+                }
+                tick
+            })
+            .collect();
+
+        let mut values = new_values();
+        values.add_data(batch(&ticks[..2]), None);
+        values.add_data(batch(&ticks[2..]), None);
+
+        assert_eq!(values.nits_ticks().cloned().collect::<Vec<_>>(), ticks);
+        assert_eq!(
+            values
+                .get_nits_senders()
+                .iter()
+                .copied()
+                .collect::<Vec<_>>(),
+            (-1..=2).map(NitsRelativeCarCount::new).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn nits_timeline_accessors_agree_oldest_first() {
+        let ticks: Vec<NitsTick> = (0..3).map(|t| NitsTick::new(commonline(t, 0))).collect();
+        let mut values = new_values();
+        values.add_data(batch(&ticks), None);
+
+        assert_eq!(
+            values.get_nits_timeline().iter().collect::<Vec<_>>(),
+            values.nits_ticks().collect::<Vec<_>>()
+        );
+        assert_eq!(values.nits_ticks().next(), ticks.first());
+        assert_eq!(values.nits_ticks().next_back(), ticks.last());
+    }
+
+    #[test]
+    fn latest_time_is_kept_across_push_clear_and_restore() {
+        let settings = Rc::new(RefCell::new(Settings {
+            keep_values: true,
+            ..Settings::default()
+        }));
+        let mut values = Values::new(Rc::clone(&settings));
+        assert_eq!(values.latest_time(), None);
+        values.add_data(HashMap::from([("a".to_owned(), vec![1.0])]), Some(10.0));
+        values.add_data(
+            HashMap::from([("b".to_owned(), vec![2.0, 3.0])]),
+            Some(20.0),
+        );
+        values.add_data(HashMap::from([("a".to_owned(), vec![4.0])]), Some(15.0));
+        // 古い時刻のバッチが来ても最新の時刻は戻らない
+        let latest = values.latest_time().unwrap();
+        assert_eq!(latest, 20.0);
+
+        // 保存していない最新の時刻は復元したときに times から求め直す
+        let json = serde_json::to_string(&values).unwrap();
+        let mut restored: Values = serde_json::from_str(&json).unwrap();
+        restored.set_settings(settings);
+        assert_eq!(restored.latest_time(), Some(latest));
+
+        values.clear();
+        assert_eq!(values.latest_time(), None);
+    }
+
+    #[test]
+    fn nits_tick_time_inverts_nits_tick_at() {
+        let ticks: Vec<NitsTick> = (0..5).map(|t| NitsTick::new(commonline(t, 0))).collect();
+        let mut values = new_values();
+        values.add_data(batch(&ticks[..2]), Some(10.0));
+        values.add_data(batch(&ticks[2..]), Some(20.0));
+
+        for tick in 0..ticks.len() {
+            let time = values.nits_tick_time(tick).unwrap();
+            assert_eq!(values.nits_tick_at(time), Some(tick));
+        }
+        // 最新のティックは最後のバッチの時刻
+        assert_eq!(values.nits_tick_time(4), values.latest_time());
+        assert_eq!(values.nits_tick_time(5), None);
+    }
+
+    #[test]
+    fn add_data_records_nits_commands_as_channels() {
+        let sender = NitsRelativeCarCount::new(1);
+        let command = NitsCommand::from_parts(0x20, 0x1234);
+        let mut tick = NitsTick::new(commonline(0, 1));
+        tick.add_command(sender, command);
+        let mut values = new_values();
+        values.add_data(batch(&[tick]), None);
+
+        // 後方1両目は NITS N31 で届く
+        assert_eq!(
+            values_of(&values, "NITS N31"),
+            vec![f32::from_bits(command.bits()) as f64]
+        );
+        assert!(values
+            .get_nits_command_types()
+            .contains(&NitsCommandType::new(0x20)));
+        // 派生チャンネルは選ばれるまで記録しない
+        let key = nits_channel_key(&NitsSender::Command(sender), command.command_type());
+        assert!(!values.contains_key(&key));
+
+        // 選ばれたら保持しているティックから作り、その後は現れなかったティックも直前の値を保持する
+        assert!(values.enable_nits_channel(&key));
+        assert_eq!(values_of(&values, &key), vec![0x1234 as f64]);
+        values.add_data(batch(&[NitsTick::new(commonline(0, 1))]), None);
+        assert_eq!(values_of(&values, &key), vec![0x1234 as f64; 2]);
+        assert_eq!(
+            values.iter_with_time_for_key(&key).unwrap().count(),
+            values.nits_ticks().len()
+        );
+    }
+
+    #[test]
+    fn nits_channels_over_the_key_limit_are_not_recorded() {
+        let settings = Settings {
+            max_keys: 1,
+            ..Settings::default()
+        };
+        let mut values = Values::new(Rc::new(RefCell::new(settings)));
+        // 単独の編成ではコモンラインの NITS N32 だけが届く
+        values.add_data(batch(&[NitsTick::new(commonline(0, 0))]), None);
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["NITS N32"]);
+
+        let key = nits_channel_key(&NitsSender::CommonLine, NitsCommandType::new(0x01));
+        assert!(!values.enable_nits_channel(&key));
+        values.add_data(batch(&[NitsTick::new(commonline(0, 0))]), None);
+        assert!(!values.contains_key(&key));
+    }
+
+    #[test]
+    fn rejected_count_counts_batches_with_dropped_keys() {
+        let settings = Settings {
+            max_keys: 1,
+            ..Settings::default()
+        };
+        let mut values = Values::new(Rc::new(RefCell::new(settings)));
+        let data = |keys: &[&str]| {
+            keys.iter()
+                .map(|k| (k.to_string(), vec![1.0]))
+                .collect::<HashMap<_, _>>()
+        };
+        values.add_data(data(&["a"]), None);
+        assert_eq!(values.rejected_count(), 0);
+
+        // 1つのバッチで捨てたキーがいくつあっても1と数える
+        values.add_data(data(&["a", "b", "c"]), None);
+        assert_eq!(values.rejected_count(), 1);
+        values.add_data(data(&["a", "d"]), None);
+        assert_eq!(values.rejected_count(), 2);
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(values_of(&values, "a"), vec![1.0; 3]);
+    }
+
+    #[test]
+    fn short_nits_channels_align_to_the_latest_ticks() {
+        let front = NitsRelativeCarCount::new(-1);
+        let commands = [0x10, 0x11].map(|t| NitsCommand::from_parts(t, 0));
+        let as_f64 = |command: &NitsCommand| f32::from_bits(command.bits()) as f64;
+        // 前方1両の編成では前方1両目が NITS N01 で届く
+        let mut values = new_values();
+        values.add_data(
+            HashMap::from([
+                ("NITS N32".to_owned(), vec![as_f64(&commonline(1, 0)); 4]),
+                ("NITS N01".to_owned(), commands.iter().map(as_f64).collect()),
+            ]),
+            None,
+        );
+
+        let received: Vec<Option<NitsCommand>> = values
+            .nits_ticks()
+            .map(|tick| tick.commands().get(&front).copied())
+            .collect();
+        assert_eq!(
+            received,
+            vec![None, None, Some(commands[0]), Some(commands[1])]
+        );
+    }
+
+    #[test]
+    fn nits_payload_bits_resplit_held_ticks() {
+        let sender = NitsRelativeCarCount::new(0);
+        let mut tick = NitsTick::new(commonline(0, 0));
+        tick.add_command(sender, NitsCommand::from_parts(0xab, 0x12_3456));
+        let mut values = new_values();
+        values.add_data(batch(&[tick.clone()]), None);
+
+        values.set_nits_payload_bits(28);
+        // 保持しているティックも、その後に届いたティックも同じ分割で読む
+        values.add_data(batch(&[tick]), None);
+        for tick in values.nits_ticks() {
+            let command = tick.commands()[&sender];
+            assert_eq!(command.command_type(), NitsCommandType::new(0x0a));
+            assert_eq!(command.payload(), 0xb12_3456);
+        }
+        assert!(!values
+            .get_nits_command_types()
+            .contains(&NitsCommandType::new(0xab)));
+    }
+
+    #[test]
+    fn restored_nits_ticks_follow_the_payload_bits_setting() {
+        let sender = NitsRelativeCarCount::new(0);
+        let mut tick = NitsTick::new(commonline(0, 0));
+        tick.add_command(sender, NitsCommand::from_parts(0xab, 0x12_3456));
+        let settings = Rc::new(RefCell::new(Settings {
+            keep_values: true,
+            ..Settings::default()
+        }));
+        let mut values = Values::new(Rc::clone(&settings));
+        values.set_nits_payload_bits(28);
+        values.add_data(batch(&[tick]), None);
+
+        let json = serde_json::to_string(&values).unwrap();
+        let mut restored: Values = serde_json::from_str(&json).unwrap();
+        restored.set_settings(settings);
+        let command = restored.nits_ticks().next().unwrap().commands()[&sender];
+        assert_eq!(command.payload_bits(), 28);
+        assert_eq!(command.command_type(), NitsCommandType::new(0x0a));
+        assert_eq!(command.payload(), 0xb12_3456);
+    }
+
+    #[test]
+    fn computed_keys_follow_received_keys() {
+        let settings = Settings {
+            computed_keys: BTreeMap::from([("sum".to_owned(), "a + b".to_owned())]),
+            ..Settings::default()
+        };
+        let mut values = Values::new(Rc::new(RefCell::new(settings)));
+        values.add_data(
+            HashMap::from([
+                ("b".to_owned(), vec![10.0, 20.0]),
+                ("a".to_owned(), vec![1.0, 2.0]),
+            ]),
+            None,
+        );
+
+        // 計算キーは受信したキーの後に来る
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["a", "b", "sum"]);
+        let sum: Vec<f64> = values
+            .values_for_key("sum")
+            .unwrap()
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(sum, vec![11.0, 22.0]);
+    }
+
+    #[test]
+    fn csv_pads_short_columns_with_empty_cells() {
+        let mut values = new_values();
+        values.add_data(
+            HashMap::from([
+                ("a".to_owned(), vec![1.0, 2.0, 3.0, 4.0, 5.0]),
+                ("b".to_owned(), vec![10.0, 20.0, 30.0]),
+                ("c".to_owned(), vec![6.0, 7.0, 8.0, 9.0, 0.5]),
+            ]),
+            None,
+        );
+        let keys = ["a", "b", "c"].map(String::from);
+        let mut csv = Vec::new();
+        values.write_csv(&mut csv, keys.iter()).unwrap();
+
+        // 短い列は末尾 (最新) を揃え、足りない行は空欄にする
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "a,b,c\n1,,6\n2,,7\n3,10,8\n4,20,9\n5,30,0.5\n"
+        );
+    }
+
+    #[test]
+    fn csv_rows_follow_the_sample_alignment() {
+        // a は3ティック、b は2ティック目と4ティック目にだけ届く
+        let mut values = new_values();
+        for batch in [
+            vec![("a", 1.0)],
+            vec![("a", 2.0), ("b", 10.0)],
+            vec![("a", 3.0)],
+            vec![("b", 20.0)],
+        ] {
+            let data = batch
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), vec![v]))
+                .collect::<HashMap<_, _>>();
+            values.add_data(data, None);
+        }
+        let keys = ["a", "b"].map(String::from);
+        let csv = |alignment| {
+            values.settings.borrow_mut().sample_alignment = alignment;
+            let mut csv = Vec::new();
+            values.write_csv(&mut csv, keys.iter()).unwrap();
+            String::from_utf8(csv).unwrap()
+        };
+
+        assert_eq!(csv(SampleAlignment::End), "a,b\n1,\n2,10\n3,20\n");
+        assert_eq!(csv(SampleAlignment::Start), "a,b\n1,10\n2,20\n3,\n");
+        assert_eq!(csv(SampleAlignment::Timestamp), "a,b\n1,\n2,10\n3,\n,20\n");
+    }
+
+    fn values_of(values: &Values, key: &str) -> Vec<f64> {
+        values
+            .values_for_key(key)
+            .unwrap()
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn missing_values_skip_nits_channels_and_exempt_keys() {
+        let settings = Settings {
+            missing_value: Some(0.0),
+            missing_values: BTreeMap::from([("b".to_owned(), 99.0)]),
+            missing_value_exempt: BTreeSet::from(["c".to_owned()]),
+            ..Settings::default()
+        };
+        let mut values = Values::new(Rc::new(RefCell::new(settings)));
+        let derived = nits_channel_key(&NitsSender::CommonLine, NitsCommandType::new(0x00));
+        assert!(values.enable_nits_channel(&derived));
+        let mut data = batch(&[NitsTick::new(NitsCommand::from_parts(0x00, 0))]);
+        data.insert("a".to_owned(), vec![1.0, 0.0]);
+        data.insert("b".to_owned(), vec![0.0, 99.0]);
+        data.insert("c".to_owned(), vec![0.0, 2.0]);
+        values.add_data(data, None);
+
+        // グローバルの欠測値
+        let a = values_of(&values, "a");
+        assert_eq!(a[0], 1.0);
+        assert!(a[1].is_nan());
+        // キーごとの欠測値がグローバルより優先される
+        let b = values_of(&values, "b");
+        assert_eq!(b[0], 0.0);
+        assert!(b[1].is_nan());
+        // グローバルの欠測値を無視するキー
+        assert_eq!(values_of(&values, "c"), vec![0.0, 2.0]);
+        // NITS の生チャンネルと派生チャンネルは欠測値にしない
+        assert_eq!(values_of(&values, "NITS N32"), vec![0.0]);
+        assert_eq!(values_of(&values, &derived), vec![0.0]);
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// WebSocket で受け取るデータの形式
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum WireFormat {
+    // {"key": [値, ...], ...} の JSON テキスト
+    #[default]
+    JsonText,
+    // リトルエンディアンのバイナリ
+    //   u32 キーの数
+    //   キーごとに u32 名前のバイト数, UTF-8 の名前, u32 値の数, f32 の値の並び
+    BinaryV1,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
+
+// BinaryV1 のフレームを読む (途中で途切れていたり余りがあれば None)
+pub fn decode_frame(bytes: &[u8]) -> Option<HashMap<String, Vec<f64>>> {
+    let mut reader = Reader { bytes };
+    let count = reader.u32()?;
+    let mut data = HashMap::new();
+    for _ in 0..count {
+        let len = reader.u32()? as usize;
+        let key = std::str::from_utf8(reader.take(len)?).ok()?.to_owned();
+        let len = reader.u32()? as usize;
+        let values = (0..len)
+            .map(|_| reader.f32().map(f64::from))
+            .collect::<Option<Vec<_>>>()?;
+        data.insert(key, values);
+    }
+    reader.bytes.is_empty().then_some(data)
+}
+
+// JsonText のメッセージを読む
+// flatten なら入れ子のオブジェクトを "a.b" のようなキーに展開し、数値1つも長さ1の値として扱う
+// (数値でないものは読み飛ばす)
+pub fn decode_json(text: &str, flatten: bool) -> serde_json::Result<HashMap<String, Vec<f64>>> {
+    if !flatten {
+        return serde_json::from_str(text);
+    }
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    let mut data = HashMap::new();
+    flatten_value(&mut data, String::new(), &value);
+    Ok(data)
+}
+
+fn flatten_value(data: &mut HashMap<String, Vec<f64>>, key: String, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map {
+                let key = if key.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", key, k)
+                };
+                flatten_value(data, key, v);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            data.insert(key, array.iter().filter_map(|v| v.as_f64()).collect());
+        }
+        serde_json::Value::Number(n) => {
+            if let Some(n) = n.as_f64() {
+                data.insert(key, vec![n]);
+            }
+        }
+        _ => {}
+    }
+}
+
+// 受け取ったキーを renames に従って表示するキーに置き換える
+pub fn rename_keys(
+    data: HashMap<String, Vec<f64>>,
+    renames: &BTreeMap<String, String>,
+) -> HashMap<String, Vec<f64>> {
+    if renames.is_empty() {
+        return data;
+    }
+    data.into_iter()
+        .map(|(k, v)| match renames.get(&k) {
+            Some(renamed) => (renamed.clone(), v),
+            None => (k, v),
+        })
+        .collect()
+}